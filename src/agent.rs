@@ -0,0 +1,256 @@
+use anyhow::Result;
+use lapce_plugin::{
+    lsp::LspRef,
+    psp_types::{lsp_types::MessageType, Request},
+    PLUGIN_RPC,
+};
+
+use crate::{
+    copilot::{GetVersion, GetVersionParams, GetVersionResult},
+    State,
+};
+
+/// The oldest agent that the types in `copilot.rs` are known to work with, which is the one that
+/// is bundled
+const MIN_AGENT_VERSION: (u32, u32, u32) = (1, 138, 0);
+/// The first agent version that is expected to have changed the protocol
+const MAX_AGENT_VERSION: (u32, u32, u32) = (2, 0, 0);
+
+/// Where the agent is in starting up. Completions are only asked for once it is `Ready`.
+///
+/// `Starting` → `Initialized` → `Configured` → (`Authenticating` →) `Ready`, where any of them
+/// can go to `Failed`, and a restart goes back to `Starting`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AgentState {
+    /// The agent is being launched, or hasn't been yet
+    #[default]
+    Starting,
+    /// The agent has answered one of our requests, so Lapce has launched it and done the
+    /// initialize handshake with it
+    Initialized,
+    /// The agent has accepted `setEditorInfo`
+    Configured,
+    /// The user is not signed in, and is being taken through signing in
+    Authenticating,
+    /// Signed in, and ready for completions
+    Ready,
+    /// Starting the agent failed, or it stopped working
+    Failed(String),
+}
+
+impl AgentState {
+    pub fn is_ready(&self) -> bool {
+        *self == AgentState::Ready
+    }
+
+    /// Whether the agent can go from this state to `to`
+    fn can_become(&self, to: &AgentState) -> bool {
+        use AgentState::*;
+
+        matches!(
+            (self, to),
+            (_, Starting)
+                | (_, Failed(_))
+                | (Starting, Initialized)
+                | (Initialized, Configured)
+                | (Configured, Authenticating | Ready)
+                | (Authenticating, Ready)
+                // Being signed out, such as by the token being revoked
+                | (Ready, Authenticating)
+        )
+    }
+}
+
+/// The agent is older than the plugin supports, which restarting it won't change
+#[derive(Debug)]
+pub struct AgentTooOld {
+    pub version: String,
+}
+
+impl std::fmt::Display for AgentTooOld {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (major, minor, patch) = MIN_AGENT_VERSION;
+        write!(
+            f,
+            "Copilot agent {} is too old, it must be at least {major}.{minor}.{patch}",
+            self.version
+        )
+    }
+}
+
+impl std::error::Error for AgentTooOld {}
+
+/// Ask the agent for its version and check that we can talk to it. Agents that are too old are
+/// refused, while newer ones are only warned about, as they may well still work.
+/// Returns whether the agent answered, as failing to is only an error for agents that have
+/// `getVersion`.
+pub fn check_agent_version(lsp: LspRef) -> Result<bool> {
+    let resp: GetVersionResult =
+        match lsp.send_request_blocking(GetVersion::METHOD, GetVersionParams {}) {
+            Ok(resp) => resp,
+            Err(err) => {
+                // Language servers without `getVersion` are new enough to not need it
+                PLUGIN_RPC.stderr(&format!("Failed to get copilot version: {err}"));
+                return Ok(false);
+            }
+        };
+
+    PLUGIN_RPC.window_log_message(
+        MessageType::INFO,
+        format!(
+            "Copilot agent version {} ({}, {})",
+            resp.version,
+            resp.build_type.as_deref().unwrap_or("unknown build"),
+            resp.runtime_version.as_deref().unwrap_or("unknown runtime"),
+        ),
+    )?;
+
+    let Some(version) = parse_version(&resp.version) else {
+        PLUGIN_RPC.stderr(&format!(
+            "Failed to parse copilot version: {:?}",
+            resp.version
+        ));
+        return Ok(true);
+    };
+
+    if version < MIN_AGENT_VERSION {
+        return Err(AgentTooOld {
+            version: resp.version,
+        }
+        .into());
+    }
+    if version >= MAX_AGENT_VERSION {
+        PLUGIN_RPC.window_show_message(
+            MessageType::WARNING,
+            format!(
+                "Copilot agent {} is newer than this plugin knows about, so it may not work",
+                resp.version
+            ),
+        )?;
+    }
+
+    Ok(true)
+}
+
+/// Parse `major.minor.patch`, ignoring anything like `-beta` after it
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().unwrap_or(Some(0))?,
+    ))
+}
+
+impl State {
+    /// Move the agent to `state`, refusing changes that the startup order doesn't allow, such as
+    /// a sign-in finishing for an agent that has since failed.
+    pub(crate) fn set_agent_state(&mut self, state: AgentState) -> Result<()> {
+        if !self.agent_state.can_become(&state) {
+            anyhow::bail!(
+                "Unexpected copilot state change: {:?} -> {state:?}",
+                self.agent_state
+            );
+        }
+
+        PLUGIN_RPC.stderr(&format!(
+            "Copilot state: {:?} -> {state:?}",
+            self.agent_state
+        ));
+        self.agent_state = state;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::State;
+
+    #[test]
+    fn startup_goes_through_each_state() {
+        use AgentState::*;
+
+        let path = [Starting, Initialized, Configured, Authenticating, Ready];
+        for pair in path.windows(2) {
+            assert!(pair[0].can_become(&pair[1]), "{pair:?}");
+        }
+        assert!(Configured.can_become(&Ready));
+        assert!(Ready.can_become(&Authenticating));
+    }
+
+    #[test]
+    fn startup_cannot_skip_states() {
+        use AgentState::*;
+
+        assert!(!Starting.can_become(&Configured));
+        assert!(!Starting.can_become(&Ready));
+        assert!(!Initialized.can_become(&Ready));
+        assert!(!Failed("crashed".to_string()).can_become(&Ready));
+    }
+
+    #[test]
+    fn any_state_can_fail_or_restart() {
+        use AgentState::*;
+
+        let failed = Failed("crashed".to_string());
+        for state in [Starting, Initialized, Configured, Authenticating, Ready] {
+            assert!(state.can_become(&failed), "{state:?}");
+            assert!(state.can_become(&Starting), "{state:?}");
+        }
+        assert!(failed.can_become(&Starting));
+    }
+
+    #[test]
+    fn illegal_state_changes_are_rejected() {
+        let mut state = State::default();
+
+        assert!(state.set_agent_state(AgentState::Ready).is_err());
+        assert_eq!(state.agent_state, AgentState::Starting);
+
+        state.set_agent_state(AgentState::Initialized).unwrap();
+        state.set_agent_state(AgentState::Configured).unwrap();
+        state.set_agent_state(AgentState::Ready).unwrap();
+        assert_eq!(state.agent_state, AgentState::Ready);
+    }
+
+    #[test]
+    fn failed_agent_only_restarts() {
+        let mut state = State::default();
+        let failed = AgentState::Failed("crashed".to_string());
+        state.set_agent_state(failed.clone()).unwrap();
+
+        assert!(state.set_agent_state(AgentState::Configured).is_err());
+        assert!(state.set_agent_state(AgentState::Authenticating).is_err());
+        assert_eq!(state.agent_state, failed);
+
+        state.set_agent_state(AgentState::Starting).unwrap();
+        assert_eq!(state.agent_state, AgentState::Starting);
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(parse_version("1.138.0"), Some((1, 138, 0)));
+        assert_eq!(parse_version("1.140.2-beta.1"), Some((1, 140, 2)));
+        assert_eq!(parse_version("2.0.0+build.5"), Some((2, 0, 0)));
+        assert_eq!(parse_version("1.139"), Some((1, 139, 0)));
+    }
+
+    #[test]
+    fn rejects_bad_versions() {
+        assert_eq!(parse_version(""), None);
+        assert_eq!(parse_version("1"), None);
+        assert_eq!(parse_version("one.two.three"), None);
+        assert_eq!(parse_version("1.x.0"), None);
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert!(parse_version("1.138.0").unwrap() >= MIN_AGENT_VERSION);
+        assert!(parse_version("1.99.0").unwrap() < MIN_AGENT_VERSION);
+        assert!(parse_version("1.1000.0").unwrap() < MAX_AGENT_VERSION);
+    }
+}
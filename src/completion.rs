@@ -0,0 +1,283 @@
+use lapce_plugin::psp_types::lsp_types::{
+    Command, InlineCompletionItem, InsertTextFormat, Position, Url,
+};
+
+use crate::copilot::Completion;
+
+/// Command that Lapce runs when an inline completion is accepted.  
+/// Arguments are the document uri and the uuid of the accepted completion.
+pub const ACCEPT_COMMAND: &str = "lapce-copilot.acceptCompletion";
+
+/// Convert a Copilot completion into an inline completion item for Lapce.
+///
+/// Copilot's `text` is the entire line as it would look after accepting the suggestion, and
+/// `range` covers the part of the existing line that it replaces, which starts at the beginning
+/// of the line and can extend past the cursor when completing in the middle of a line.  
+/// `display_text` is only the text after the cursor, and so it can't be inserted without
+/// knowing what the user has already typed, nor can it replace the remainder of the line.
+pub fn to_inline_completion_item(uri: &Url, completion: Completion) -> InlineCompletionItem {
+    InlineCompletionItem {
+        insert_text: completion.text,
+        insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
+        filter_text: None,
+        range: Some(completion.range),
+        command: Some(Command {
+            title: "Accept Copilot completion".to_string(),
+            command: ACCEPT_COMMAND.to_string(),
+            arguments: Some(vec![uri.to_string().into(), completion.uuid.into()]),
+        }),
+    }
+}
+
+/// Completions from `getCompletionsCycling`, which are kept so that invoking completion again at
+/// the same place steps through the alternatives rather than asking Copilot again.
+pub struct Cycling {
+    pub version: i32,
+    pub position: Position,
+    pub completions: Vec<Completion>,
+    /// Index of the completion that is currently shown first
+    pub index: usize,
+}
+
+impl Cycling {
+    pub fn new(version: i32, position: Position, completions: Vec<Completion>) -> Cycling {
+        Cycling {
+            version,
+            position,
+            completions,
+            index: 0,
+        }
+    }
+
+    /// Whether these are the completions for the document at `version` and `position`
+    pub fn is_for(&self, version: i32, position: Position) -> bool {
+        self.version == version && self.position == position && !self.completions.is_empty()
+    }
+
+    /// Step to the next alternative, getting the completions in the order to show them in
+    pub fn cycle(&mut self) -> Vec<Completion> {
+        self.index = (self.index + 1) % self.completions.len();
+
+        let (before, after) = self.completions.split_at(self.index);
+        after.iter().chain(before).cloned().collect()
+    }
+}
+
+/// Completions from the last automatic request for a document, along with the line that they
+/// were made for, so that they can still be served while the user types what was suggested.
+pub struct Suggestions {
+    pub position: Position,
+    /// The text of the line before `position`
+    pub before: String,
+    /// The text of the line after `position`
+    pub after: String,
+    pub completions: Vec<Completion>,
+}
+
+impl Suggestions {
+    /// Get the completions that are still valid for the cursor being at `position`, where the
+    /// line is split into `before` and `after` it.  
+    /// This is the case when the user has typed the start of the suggestions.
+    pub fn continue_at(&self, position: Position, before: &str, after: &str) -> Vec<Completion> {
+        if position.line != self.position.line || after != self.after {
+            return Vec::new();
+        }
+
+        let Some(typed) = before.strip_prefix(self.before.as_str()) else {
+            return Vec::new();
+        };
+        let shift = typed.encode_utf16().count() as u32;
+
+        self.completions
+            .iter()
+            .filter_map(|completion| {
+                let display_text = completion.display_text.strip_prefix(typed)?;
+                if display_text.is_empty() {
+                    return None;
+                }
+
+                // The full line text stays the same, but what it replaces has grown by what
+                // was typed
+                let mut completion = completion.clone();
+                completion.display_text = display_text.to_string();
+                completion.position = position;
+                completion.range.end.character += shift;
+
+                Some(completion)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapce_plugin::psp_types::lsp_types::{Range, TextDocumentContentChangeEvent};
+
+    use super::*;
+    use crate::document::Document;
+
+    fn completion(uuid: &str, text: &str, line: u32, end: u32, cursor: u32) -> Completion {
+        let before: String = text.chars().take(cursor as usize).collect();
+        Completion {
+            uuid: uuid.to_string(),
+            text: text.to_string(),
+            range: Range::new(Position::new(line, 0), Position::new(line, end)),
+            display_text: text[before.len()..].to_string(),
+            position: Position::new(line, cursor),
+            doc_version: 1,
+        }
+    }
+
+    fn uri() -> Url {
+        Url::parse("file:///project/main.rs").unwrap()
+    }
+
+    #[test]
+    fn item_replaces_the_typed_prefix() {
+        // The user has typed `let x` and Copilot suggests the whole line
+        let item = to_inline_completion_item(&uri(), completion("a", "let x = 1;", 3, 5, 5));
+
+        assert_eq!(item.insert_text, "let x = 1;");
+        assert_eq!(
+            item.range,
+            Some(Range::new(Position::new(3, 0), Position::new(3, 5)))
+        );
+        assert_eq!(item.insert_text_format, Some(InsertTextFormat::PLAIN_TEXT));
+    }
+
+    #[test]
+    fn item_replaces_the_rest_of_the_line_mid_line() {
+        // The cursor is between the parentheses of `foo()`, which Copilot's line keeps
+        let item = to_inline_completion_item(&uri(), completion("a", "foo(bar)", 0, 5, 4));
+
+        assert_eq!(item.insert_text, "foo(bar)");
+        assert_eq!(
+            item.range,
+            Some(Range::new(Position::new(0, 0), Position::new(0, 5)))
+        );
+    }
+
+    /// The text of a document after accepting `item` in it
+    fn accept(text: &str, item: InlineCompletionItem) -> String {
+        let mut doc = Document::new("rust".to_string(), 1, text.to_string());
+        doc.apply_change(TextDocumentContentChangeEvent {
+            range: item.range,
+            range_length: None,
+            text: item.insert_text,
+        });
+
+        doc.text
+    }
+
+    #[test]
+    fn accepting_after_a_typed_prefix_does_not_repeat_it() {
+        let text = "fn main() {\n    let x\n}\n";
+        let item = to_inline_completion_item(&uri(), completion("a", "    let x = 1;", 1, 9, 9));
+
+        assert_eq!(accept(text, item), "fn main() {\n    let x = 1;\n}\n");
+    }
+
+    #[test]
+    fn accepting_mid_line_replaces_the_rest_of_it() {
+        let text = "fn main() {\n    foo()\n}\n";
+        let item = to_inline_completion_item(&uri(), completion("a", "    foo(bar);", 1, 9, 8));
+
+        assert_eq!(accept(text, item), "fn main() {\n    foo(bar);\n}\n");
+    }
+
+    #[test]
+    fn item_accept_command_names_the_completion() {
+        let item = to_inline_completion_item(&uri(), completion("uuid-1", "x", 0, 0, 0));
+        let command = item.command.unwrap();
+
+        assert_eq!(command.command, ACCEPT_COMMAND);
+        assert_eq!(
+            command.arguments,
+            Some(vec![uri().to_string().into(), "uuid-1".into()])
+        );
+    }
+
+    #[test]
+    fn cycling_steps_through_alternatives() {
+        let completions = vec![
+            completion("a", "let a", 0, 4, 4),
+            completion("b", "let b", 0, 4, 4),
+            completion("c", "let c", 0, 4, 4),
+        ];
+        let mut cycling = Cycling::new(2, Position::new(0, 4), completions);
+
+        assert!(cycling.is_for(2, Position::new(0, 4)));
+        assert!(!cycling.is_for(3, Position::new(0, 4)));
+        assert!(!cycling.is_for(2, Position::new(0, 3)));
+
+        let order = |completions: Vec<Completion>| -> Vec<String> {
+            completions.into_iter().map(|c| c.uuid).collect()
+        };
+        assert_eq!(order(cycling.cycle()), ["b", "c", "a"]);
+        assert_eq!(order(cycling.cycle()), ["c", "a", "b"]);
+        assert_eq!(order(cycling.cycle()), ["a", "b", "c"]);
+    }
+
+    fn suggestions() -> Suggestions {
+        Suggestions {
+            position: Position::new(0, 4),
+            before: "let ".to_string(),
+            after: String::new(),
+            completions: vec![
+                completion("a", "let value = 1;", 0, 4, 4),
+                completion("b", "let other = 2;", 0, 4, 4),
+            ],
+        }
+    }
+
+    #[test]
+    fn suggestions_continue_while_typing_them() {
+        let continued = suggestions().continue_at(Position::new(0, 6), "let va", "");
+
+        assert_eq!(continued.len(), 1);
+        let completion = &continued[0];
+        assert_eq!(completion.uuid, "a");
+        assert_eq!(completion.text, "let value = 1;");
+        assert_eq!(completion.display_text, "lue = 1;");
+        assert_eq!(completion.position, Position::new(0, 6));
+        assert_eq!(completion.range.end, Position::new(0, 6));
+    }
+
+    #[test]
+    fn suggestions_stop_when_typing_something_else() {
+        let suggestions = suggestions();
+
+        assert!(suggestions
+            .continue_at(Position::new(0, 6), "let xy", "")
+            .is_empty());
+        // Deleting back past where the suggestions were made
+        assert!(suggestions
+            .continue_at(Position::new(0, 3), "let", "")
+            .is_empty());
+        assert!(suggestions
+            .continue_at(Position::new(1, 6), "let va", "")
+            .is_empty());
+        assert!(suggestions
+            .continue_at(Position::new(0, 6), "let va", ")")
+            .is_empty());
+        // Typing all of it leaves nothing to suggest
+        assert!(suggestions
+            .continue_at(Position::new(0, 14), "let value = 1;", "")
+            .is_empty());
+    }
+
+    #[test]
+    fn suggestions_shift_by_utf16_length() {
+        let suggestions = Suggestions {
+            position: Position::new(0, 1),
+            before: "\"".to_string(),
+            after: String::new(),
+            completions: vec![completion("a", "\"🔑 key\"", 0, 1, 1)],
+        };
+
+        let continued = suggestions.continue_at(Position::new(0, 3), "\"🔑", "");
+        assert_eq!(continued.len(), 1);
+        assert_eq!(continued[0].range.end, Position::new(0, 3));
+        assert_eq!(continued[0].display_text, " key\"");
+    }
+}
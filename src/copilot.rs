@@ -78,11 +78,12 @@ pub struct EditorConfiguration {
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LanguageId {
-    pub language_ids: Vec<String>,
+    pub language_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NetworkProxy {
     pub host: String,
@@ -201,6 +202,31 @@ pub struct SignInConfirmResult {
     pub user: Option<String>,
 }
 
+#[derive(Debug)]
+pub enum GetVersion {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetVersionParams {}
+
+impl Request for GetVersion {
+    type Params = GetVersionParams;
+
+    type Result = GetVersionResult;
+
+    const METHOD: &'static str = "getVersion";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetVersionResult {
+    /// Such as "1.138.0"
+    pub version: String,
+    /// "prod" | ?
+    pub build_type: Option<String>,
+    /// Such as "node/20.5.0"
+    pub runtime_version: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum SignOut {}
 
@@ -286,7 +312,7 @@ pub struct GetCompletionsResult {
     pub completions: Vec<Completion>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Completion {
     pub uuid: String,
@@ -299,13 +325,85 @@ pub struct Completion {
     pub doc_version: u64,
 }
 
-/// I think this is supposed to be sent when the completion is shown?
+/// Ask for a number of alternative solutions at a position, to show side by side.  
+/// Copilot replies with how many it is trying to find, and then sends each solution as a
+/// [`PanelSolution`] notification, ending with [`PanelSolutionsDone`].
+#[derive(Debug)]
+pub enum GetPanelCompletions {}
+
+impl Request for GetPanelCompletions {
+    type Params = GetPanelCompletionsParams;
+
+    type Result = GetPanelCompletionsResult;
+
+    const METHOD: &'static str = "getPanelCompletions";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetPanelCompletionsParams {
+    pub doc: GetCompletionsDoc,
+    /// Identifies the panel in the notifications that Copilot sends back
+    pub panel_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetPanelCompletionsResult {
+    pub solution_count_target: u32,
+}
+
+#[derive(Debug)]
+pub enum PanelSolution {}
+
+impl Notification for PanelSolution {
+    type Params = PanelSolutionParams;
+
+    const METHOD: &'static str = "PanelSolution";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PanelSolutionParams {
+    pub panel_id: String,
+    /// Text that replaces `range`
+    pub completion_text: String,
+    pub display_text: String,
+    pub range: Range,
+    /// Missing when Copilot couldn't score the solution
+    pub score: Option<f64>,
+    pub solution_id: String,
+}
+
+#[derive(Debug)]
+pub enum PanelSolutionsDone {}
+
+impl Notification for PanelSolutionsDone {
+    type Params = PanelSolutionsDoneParams;
+
+    const METHOD: &'static str = "PanelSolutionsDone";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PanelSolutionsDoneParams {
+    pub panel_id: String,
+    /// "OK" | "Error"
+    pub status: String,
+    pub message: Option<String>,
+}
+
+/// Sent when a completion has been shown to the user
 #[derive(Debug)]
 pub enum NotifyShown {}
 
-impl Notification for NotifyShown {
+impl Request for NotifyShown {
     type Params = NotifyShownParams;
 
+    type Result = String;
+
     const METHOD: &'static str = "notifyShown";
 }
 
@@ -316,13 +414,15 @@ pub struct NotifyShownParams {
     pub options: Option<Value>,
 }
 
-/// This is supposed to be sent when a completion is accepted
+/// Sent when a completion is accepted
 #[derive(Debug)]
 pub enum NotifyAccepted {}
 
-impl Notification for NotifyAccepted {
+impl Request for NotifyAccepted {
     type Params = NotifyAcceptedParams;
 
+    type Result = String;
+
     const METHOD: &'static str = "notifyAccepted";
 }
 
@@ -333,32 +433,35 @@ pub struct NotifyAcceptedParams {
     pub options: Option<Value>,
 }
 
-/// This is supposed to be sent when a completion is rejected
+/// Sent when completions were shown but the user moved on without accepting any of them
 #[derive(Debug)]
 pub enum NotifyRejected {}
 
-impl Notification for NotifyRejected {
+impl Request for NotifyRejected {
     type Params = NotifyRejectedParams;
 
+    type Result = String;
+
     const METHOD: &'static str = "notifyRejected";
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NotifyRejectedParams {
-    pub uuid: String,
+    pub uuids: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<Value>,
 }
 
+/// Cancel a request that we sent to Copilot.  
+/// This is the standard LSP `$/cancelRequest`, Copilot then replies to the cancelled request
+/// with an error.
 #[derive(Debug)]
 pub enum Cancel {}
 
-impl Request for Cancel {
+impl Notification for Cancel {
     type Params = CancelParams;
 
-    type Result = CancelResult;
-
-    const METHOD: &'static str = "cancel";
+    const METHOD: &'static str = "$/cancelRequest";
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -366,8 +469,3 @@ pub struct CancelParams {
     /// Request id
     pub id: u64,
 }
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CancelResult {
-    // ??
-}
@@ -0,0 +1,308 @@
+use std::{cell::OnceCell, ops::Range};
+
+use lapce_plugin::psp_types::lsp_types::{Position, TextDocumentContentChangeEvent};
+
+/// How a document is indented, where it is known
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Indentation {
+    pub insert_spaces: Option<bool>,
+    pub tab_size: Option<u16>,
+}
+impl Indentation {
+    /// Use the settings from `other` where we don't have our own
+    pub fn or(self, other: Indentation) -> Indentation {
+        Indentation {
+            insert_spaces: self.insert_spaces.or(other.insert_spaces),
+            tab_size: self.tab_size.or(other.tab_size),
+        }
+    }
+}
+
+/// Our copy of a document that is open in Lapce, kept up to date with the changes that Lapce
+/// sends us.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub language_id: String,
+    pub version: i32,
+    pub text: String,
+    /// Indentation configured by the project, such as through `.editorconfig`
+    pub configured_indentation: Indentation,
+    /// Byte ranges of the secrets in the text, once they have been looked for. They are forgotten
+    /// whenever the text changes.
+    pub secrets: OnceCell<Vec<Range<usize>>>,
+}
+
+impl Document {
+    pub fn new(language_id: String, version: i32, text: String) -> Document {
+        Document {
+            language_id,
+            version,
+            text,
+            configured_indentation: Indentation::default(),
+            secrets: OnceCell::new(),
+        }
+    }
+
+    /// The indentation of the document, from the project's configuration or otherwise guessed
+    /// from its contents.
+    pub fn indentation(&self) -> Indentation {
+        self.configured_indentation.or(self.detect_indentation())
+    }
+
+    /// Guess how the document is indented by looking at the indentation of its lines.
+    fn detect_indentation(&self) -> Indentation {
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+        // How often each change in indentation between lines occurs
+        let mut deltas = [0usize; 9];
+        let mut previous = 0;
+        for line in self.text.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            if line.starts_with('\t') {
+                tab_lines += 1;
+                continue;
+            }
+
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            if indent > 0 {
+                space_lines += 1;
+            }
+
+            let delta = indent.abs_diff(previous);
+            if (2..deltas.len()).contains(&delta) {
+                deltas[delta] += 1;
+            }
+            previous = indent;
+        }
+
+        if tab_lines == 0 && space_lines == 0 {
+            return Indentation::default();
+        }
+
+        let insert_spaces = space_lines >= tab_lines;
+        let tab_size = deltas
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .max_by_key(|(_, count)| **count)
+            .map(|(size, _)| size as u16)
+            .filter(|_| insert_spaces);
+
+        Indentation {
+            insert_spaces: Some(insert_spaces),
+            tab_size,
+        }
+    }
+
+    /// Apply a change from `textDocument/didChange`.
+    /// Changes without a range replace the entire document.
+    pub fn apply_change(&mut self, change: TextDocumentContentChangeEvent) {
+        self.secrets.take();
+
+        let Some(range) = change.range else {
+            self.text = change.text;
+            return;
+        };
+
+        let start = self.offset_at(range.start);
+        let end = self.offset_at(range.end).max(start);
+        self.text.replace_range(start..end, &change.text);
+    }
+
+    /// Get the byte offset into the text of an LSP position.
+    /// LSP positions count characters in UTF-16 code units, and positions past the end of a line
+    /// or the end of the document are clamped to them.
+    pub fn offset_at(&self, position: Position) -> usize {
+        let Some(line_start) = self.line_start(position.line) else {
+            return self.text.len();
+        };
+
+        let line = self.line_at(line_start);
+        let mut character = 0;
+        for (i, c) in line.char_indices() {
+            if character >= position.character as usize {
+                return line_start + i;
+            }
+            character += c.len_utf16();
+        }
+
+        line_start + line.len()
+    }
+
+    /// Get the text of the line of `position` split at it, without the line ending.
+    pub fn split_line_at(&self, position: Position) -> Option<(&str, &str)> {
+        let line_start = self.line_start(position.line)?;
+        let line = self.line_at(line_start);
+        let offset = self.offset_at(position) - line_start;
+
+        Some(line.split_at(offset))
+    }
+
+    /// Byte offset of the start of the line
+    fn line_start(&self, line: u32) -> Option<usize> {
+        if line == 0 {
+            return Some(0);
+        }
+
+        self.text
+            .match_indices('\n')
+            .nth(line as usize - 1)
+            .map(|(i, _)| i + 1)
+    }
+
+    /// The text of the line starting at `line_start`, without its line ending
+    fn line_at(&self, line_start: usize) -> &str {
+        let rest = &self.text[line_start..];
+        let line = rest.split('\n').next().unwrap_or(rest);
+        line.strip_suffix('\r').unwrap_or(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapce_plugin::psp_types::lsp_types::Range;
+
+    use super::*;
+
+    fn document(text: &str) -> Document {
+        Document::new("rust".to_string(), 1, text.to_string())
+    }
+
+    fn change(
+        range: Option<((u32, u32), (u32, u32))>,
+        text: &str,
+    ) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: range.map(|(start, end)| {
+                Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+            }),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn offset_at_counts_utf16() {
+        // `é` is one UTF-16 unit and two bytes, `🔑` is two UTF-16 units and four bytes
+        let doc = document("aé🔑b\nsecond");
+
+        assert_eq!(doc.offset_at(Position::new(0, 0)), 0);
+        assert_eq!(doc.offset_at(Position::new(0, 1)), 1);
+        assert_eq!(doc.offset_at(Position::new(0, 2)), 3);
+        assert_eq!(doc.offset_at(Position::new(0, 4)), 7);
+        assert_eq!(doc.offset_at(Position::new(0, 5)), 8);
+        assert_eq!(doc.offset_at(Position::new(1, 3)), 12);
+    }
+
+    #[test]
+    fn offset_at_clamps_to_the_line_and_document() {
+        let doc = document("ab\r\ncd");
+
+        // Past the end of a line is its end, before the line ending
+        assert_eq!(doc.offset_at(Position::new(0, 10)), 2);
+        assert_eq!(doc.offset_at(Position::new(1, 10)), 6);
+        assert_eq!(doc.offset_at(Position::new(5, 0)), 6);
+    }
+
+    #[test]
+    fn split_line_at_the_cursor() {
+        let doc = document("fn main() {\n    let x = 1;\n}");
+
+        assert_eq!(
+            doc.split_line_at(Position::new(1, 8)),
+            Some(("    let ", "x = 1;"))
+        );
+        assert_eq!(doc.split_line_at(Position::new(2, 0)), Some(("", "}")));
+        assert_eq!(doc.split_line_at(Position::new(3, 0)), None);
+    }
+
+    #[test]
+    fn apply_incremental_changes() {
+        let mut doc = document("hello world\nsecond line");
+
+        doc.apply_change(change(Some(((0, 6), (0, 11))), "there"));
+        assert_eq!(doc.text, "hello there\nsecond line");
+
+        // Inserting
+        doc.apply_change(change(Some(((1, 0), (1, 0))), "a "));
+        assert_eq!(doc.text, "hello there\na second line");
+
+        // Deleting across lines
+        doc.apply_change(change(Some(((0, 11), (1, 2))), " "));
+        assert_eq!(doc.text, "hello there second line");
+
+        // Adding lines
+        doc.apply_change(change(Some(((0, 5), (0, 11))), "\n\n"));
+        assert_eq!(doc.text, "hello\n\n second line");
+    }
+
+    #[test]
+    fn apply_change_with_utf16_positions() {
+        let mut doc = document("let key = \"🔑\";");
+
+        doc.apply_change(change(Some(((0, 11), (0, 13))), "🗝️"));
+        assert_eq!(doc.text, "let key = \"🗝️\";");
+    }
+
+    #[test]
+    fn apply_change_without_range_replaces_everything() {
+        let mut doc = document("old");
+
+        doc.apply_change(change(None, "new\ntext"));
+        assert_eq!(doc.text, "new\ntext");
+    }
+
+    #[test]
+    fn apply_change_with_reversed_range_inserts() {
+        let mut doc = document("abc");
+
+        doc.apply_change(change(Some(((0, 2), (0, 1))), "X"));
+        assert_eq!(doc.text, "abXc");
+    }
+
+    #[test]
+    fn detects_indentation() {
+        let spaces = document("fn main() {\n  if x {\n    y();\n  }\n}\n");
+        assert_eq!(
+            spaces.indentation(),
+            Indentation {
+                insert_spaces: Some(true),
+                tab_size: Some(2),
+            }
+        );
+
+        let tabs = document("fn main() {\n\tif x {\n\t\ty();\n\t}\n}\n");
+        assert_eq!(
+            tabs.indentation(),
+            Indentation {
+                insert_spaces: Some(false),
+                tab_size: None,
+            }
+        );
+
+        assert_eq!(
+            document("no indentation").indentation(),
+            Indentation::default()
+        );
+    }
+
+    #[test]
+    fn configured_indentation_takes_precedence() {
+        let mut doc = document("a\n  b\n    c\n");
+        doc.configured_indentation = Indentation {
+            insert_spaces: None,
+            tab_size: Some(8),
+        };
+
+        assert_eq!(
+            doc.indentation(),
+            Indentation {
+                insert_spaces: Some(true),
+                tab_size: Some(8),
+            }
+        );
+    }
+}
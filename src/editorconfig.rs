@@ -0,0 +1,339 @@
+//! Minimal `.editorconfig` support, only for what Copilot wants to know about indentation.
+//! <https://editorconfig.org/#file-format-details>
+
+use std::path::{Path, PathBuf};
+
+use globset::GlobBuilder;
+use lapce_plugin::PLUGIN_RPC;
+
+use crate::{document::Indentation, host, State};
+
+struct Section {
+    glob: String,
+    properties: Vec<(String, String)>,
+}
+
+struct EditorConfig {
+    dir: PathBuf,
+    root: bool,
+    sections: Vec<Section>,
+}
+
+/// Get the indentation for the file at `path` from the `.editorconfig` files in its directory
+/// and its parents up to `root`, whose contents are given by `read` for each directory.
+/// Files outside of `root` only use the `.editorconfig` in their own directory.
+pub fn indentation(
+    path: &Path,
+    root: Option<&Path>,
+    mut read: impl FnMut(&Path) -> String,
+) -> Indentation {
+    let root = root.filter(|root| path.starts_with(root)).or(path.parent());
+
+    let mut configs = Vec::new();
+    for dir in path.ancestors().skip(1) {
+        if root.is_some_and(|root| !dir.starts_with(root)) {
+            break;
+        }
+
+        let content = read(dir);
+        if content.is_empty() {
+            continue;
+        }
+
+        let config = parse(dir, &content);
+        let is_root = config.root;
+        configs.push(config);
+        if is_root {
+            break;
+        }
+    }
+
+    // Closer files take precedence, so they're applied last
+    let mut indentation = Indentation::default();
+    let mut indent_size = None;
+    for config in configs.iter().rev() {
+        let Ok(relative) = path.strip_prefix(&config.dir) else {
+            continue;
+        };
+        let relative = relative.to_string_lossy().replace('\\', "/");
+
+        for section in config.sections.iter() {
+            if !matches(&section.glob, &relative) {
+                continue;
+            }
+
+            for (key, value) in section.properties.iter() {
+                match (key.as_str(), value.as_str()) {
+                    ("indent_style", "space") => indentation.insert_spaces = Some(true),
+                    ("indent_style", "tab") => indentation.insert_spaces = Some(false),
+                    ("indent_size", size) => indent_size = Some(size.to_string()),
+                    ("tab_width", width) => {
+                        if let Ok(width) = width.parse() {
+                            indentation.tab_size = Some(width);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // `indent_size = tab` means to use `tab_width`, which is what `tab_size` already holds
+    if let Some(size) = indent_size.and_then(|size| size.parse().ok()) {
+        indentation.tab_size = Some(size);
+    }
+
+    indentation
+}
+
+impl State {
+    /// Get the indentation for the file at `path` from its `.editorconfig` files
+    pub(crate) fn configured_indentation(&mut self, path: &Path) -> Indentation {
+        indentation(path, self.workspace_root.as_deref(), |dir| {
+            self.editorconfigs
+                .entry(dir.to_path_buf())
+                .or_insert_with(|| {
+                    // Workspace files are outside of the sandbox, so the host reads them for us
+                    host::read_file(&dir.join(".editorconfig")).unwrap_or_else(|err| {
+                        PLUGIN_RPC.stderr(&format!("{err}"));
+                        String::new()
+                    })
+                })
+                .clone()
+        })
+    }
+
+    /// Forget the `.editorconfig` files that no open document is under
+    pub(crate) fn evict_editorconfigs(&mut self) {
+        let paths: Vec<PathBuf> = self
+            .documents
+            .keys()
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect();
+
+        self.editorconfigs
+            .retain(|dir, _| paths.iter().any(|path| path.starts_with(dir)));
+    }
+}
+
+fn parse(dir: &Path, content: &str) -> EditorConfig {
+    let mut config = EditorConfig {
+        dir: dir.to_path_buf(),
+        root: false,
+        sections: Vec::new(),
+    };
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(glob) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            config.sections.push(Section {
+                glob: glob.to_string(),
+                properties: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let value = value.trim().to_lowercase();
+
+        match config.sections.last_mut() {
+            Some(section) => section.properties.push((key, value)),
+            // Properties before the first section are the preamble
+            None => {
+                if key == "root" {
+                    config.root = value == "true";
+                }
+            }
+        }
+    }
+
+    config
+}
+
+/// Check whether a section's glob matches `relative`, the path of the file relative to the
+/// directory of the `.editorconfig`
+fn matches(glob: &str, relative: &str) -> bool {
+    // Globs without a slash match the file name in any directory
+    let glob = if glob.contains('/') {
+        glob.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{glob}")
+    };
+    // `**` matches across directories even when it isn't a whole path component, as in
+    // `src/**.rs`, which globset would treat as `*`
+    let glob = glob.replace("**", "**/*").replace("**/*/", "**/");
+
+    match GlobBuilder::new(&glob).literal_separator(true).build() {
+        Ok(glob) => glob.compile_matcher().is_match(relative),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use lapce_plugin::psp_types::lsp_types::Url;
+
+    use super::*;
+    use crate::document::Document;
+
+    /// The indentation of `path` with `.editorconfig` files of `files`, by directory
+    fn indentation_with(path: &str, files: &[(&str, &str)]) -> Indentation {
+        let files: HashMap<PathBuf, String> = files
+            .iter()
+            .map(|(dir, content)| (PathBuf::from(dir), content.to_string()))
+            .collect();
+
+        indentation(Path::new(path), Some(Path::new("/")), |dir| {
+            files.get(dir).cloned().unwrap_or_default()
+        })
+    }
+
+    #[test]
+    fn reads_indentation() {
+        let indentation = indentation_with(
+            "/project/src/main.rs",
+            &[(
+                "/project",
+                "root = true\n[*]\nindent_style = space\nindent_size = 2\n",
+            )],
+        );
+
+        assert_eq!(indentation.insert_spaces, Some(true));
+        assert_eq!(indentation.tab_size, Some(2));
+    }
+
+    #[test]
+    fn indent_size_tab_uses_tab_width() {
+        let indentation = indentation_with(
+            "/project/Makefile",
+            &[(
+                "/project",
+                "[Makefile]\nindent_style = tab\nindent_size = tab\ntab_width = 8\n",
+            )],
+        );
+
+        assert_eq!(indentation.insert_spaces, Some(false));
+        assert_eq!(indentation.tab_size, Some(8));
+    }
+
+    #[test]
+    fn closer_files_take_precedence() {
+        let indentation = indentation_with(
+            "/project/web/app.js",
+            &[
+                ("/project", "[*]\nindent_style = tab\nindent_size = 4\n"),
+                ("/project/web", "[*.js]\nindent_size = 2\n"),
+            ],
+        );
+
+        assert_eq!(indentation.insert_spaces, Some(false));
+        assert_eq!(indentation.tab_size, Some(2));
+    }
+
+    #[test]
+    fn root_stops_the_search() {
+        let indentation = indentation_with(
+            "/project/web/app.js",
+            &[
+                ("/", "[*]\nindent_style = tab\n"),
+                ("/project", "root = true\n[*]\nindent_size = 3\n"),
+            ],
+        );
+
+        assert_eq!(indentation.insert_spaces, None);
+        assert_eq!(indentation.tab_size, Some(3));
+    }
+
+    #[test]
+    fn search_stops_at_the_workspace_root() {
+        let mut read = Vec::new();
+        indentation(
+            Path::new("/home/user/project/src/main.rs"),
+            Some(Path::new("/home/user/project")),
+            |dir| {
+                read.push(dir.to_path_buf());
+                String::new()
+            },
+        );
+
+        assert_eq!(
+            read,
+            [
+                PathBuf::from("/home/user/project/src"),
+                PathBuf::from("/home/user/project"),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_outside_the_workspace_only_use_their_directory() {
+        let mut read = Vec::new();
+        indentation(
+            Path::new("/etc/nginx/nginx.conf"),
+            Some(Path::new("/home/user/project")),
+            |dir| {
+                read.push(dir.to_path_buf());
+                String::new()
+            },
+        );
+
+        assert_eq!(read, [PathBuf::from("/etc/nginx")]);
+    }
+
+    #[test]
+    fn unneeded_files_are_evicted() {
+        let mut state = State::default();
+        for dir in ["/project", "/project/web", "/project/src", "/etc"] {
+            state
+                .editorconfigs
+                .insert(PathBuf::from(dir), String::new());
+        }
+        let uri = Url::from_file_path("/project/src/main.rs").unwrap();
+        let document = Document::new("rust".to_string(), 1, String::new());
+        state.documents.insert(uri, document);
+
+        state.evict_editorconfigs();
+
+        let mut dirs: Vec<_> = state.editorconfigs.into_keys().collect();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            [PathBuf::from("/project"), PathBuf::from("/project/src")]
+        );
+    }
+
+    #[test]
+    fn sections_that_do_not_match_are_ignored() {
+        let indentation = indentation_with(
+            "/project/src/main.rs",
+            &[(
+                "/project",
+                "[*.py]\nindent_style = space\n[docs/*]\nindent_size = 8\n",
+            )],
+        );
+
+        assert_eq!(indentation, Indentation::default());
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("*", "main.rs"));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(matches("{*.js,*.ts}", "web/app.ts"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(matches("/src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("src/*.rs", "lib/src/main.rs"));
+        assert!(matches("src/**.rs", "src/bin/main.rs"));
+        assert!(!matches("*.py", "main.rs"));
+    }
+}
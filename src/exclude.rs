@@ -0,0 +1,220 @@
+//! Which documents must never be sent to Copilot, from the `.copilotignore` at the root of the
+//! workspace and the globs in the `exclude.globs` setting.
+
+use std::path::{Path, PathBuf};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use lapce_plugin::{
+    psp_types::lsp_types::{MessageType, Url},
+    PLUGIN_RPC,
+};
+
+use crate::{host, State};
+
+/// Name of the file at the root of the workspace, in gitignore syntax, of paths that are excluded
+pub const IGNORE_FILE: &str = ".copilotignore";
+
+#[derive(Default)]
+pub struct Exclusions {
+    /// The `.copilotignore` of the workspace, if it has one
+    ignore: Option<Gitignore>,
+    /// The root of the workspace, if its `.copilotignore` couldn't be read. Then nothing in the
+    /// workspace is known to be safe to send, so all of it is excluded.
+    unreadable: Option<PathBuf>,
+    /// The user's globs, which apply to every workspace
+    globs: GlobSet,
+}
+
+impl Exclusions {
+    /// Exclusions from the `.copilotignore` at `workspace_root` with the contents `ignore_file`,
+    /// which is empty if there is none, and from the user's globs
+    pub fn new(workspace_root: Option<&Path>, ignore_file: &str, globs: &[String]) -> Exclusions {
+        Exclusions {
+            ignore: workspace_root.and_then(|root| build_ignore_file(root, ignore_file)),
+            unreadable: None,
+            globs: build_globs(globs),
+        }
+    }
+
+    /// Exclusions for a workspace whose `.copilotignore` couldn't be read
+    pub fn unreadable(workspace_root: &Path, globs: &[String]) -> Exclusions {
+        Exclusions {
+            ignore: None,
+            unreadable: Some(workspace_root.to_path_buf()),
+            globs: build_globs(globs),
+        }
+    }
+
+    /// Whether the document at `uri` must not be sent to Copilot.
+    /// Documents that aren't files, like new unsaved ones, are never excluded.
+    pub fn is_excluded(&self, uri: &Url) -> bool {
+        let Ok(path) = uri.to_file_path() else {
+            return false;
+        };
+
+        if self
+            .unreadable
+            .as_ref()
+            .is_some_and(|root| path.starts_with(root))
+        {
+            return true;
+        }
+
+        if let Some(ignore) = &self.ignore {
+            if path.starts_with(ignore.path())
+                && ignore.matched_path_or_any_parents(&path, false).is_ignore()
+            {
+                return true;
+            }
+        }
+
+        self.globs
+            .is_match(path.to_string_lossy().replace('\\', "/"))
+    }
+}
+
+impl State {
+    /// Load the exclusions for the workspace and the current settings
+    pub(crate) fn load_exclusions(&mut self) {
+        let globs = &self.settings.exclude.globs;
+        let Some(root) = self.workspace_root.as_deref() else {
+            self.exclusions = Exclusions::new(None, "", globs);
+            return;
+        };
+
+        // Workspace files are outside of the sandbox, so the host reads them for us
+        self.exclusions = match host::read_file(&root.join(IGNORE_FILE)) {
+            Ok(ignore_file) => Exclusions::new(Some(root), &ignore_file, globs),
+            Err(err) => {
+                PLUGIN_RPC.stderr(&format!("{err}"));
+                let _ = PLUGIN_RPC.window_show_message(
+                    MessageType::WARNING,
+                    format!(
+                        "Copilot is not used for {root:?}, as its {IGNORE_FILE} could not be read"
+                    ),
+                );
+                Exclusions::unreadable(root, globs)
+            }
+        };
+    }
+}
+
+fn build_ignore_file(root: &Path, content: &str) -> Option<Gitignore> {
+    if content.trim().is_empty() {
+        return None;
+    }
+
+    let path = root.join(IGNORE_FILE);
+    let mut builder = GitignoreBuilder::new(root);
+    // Bad lines are skipped rather than throwing out the whole file, as that would send everything
+    for line in content.lines() {
+        if let Err(err) = builder.add_line(Some(path.clone()), line) {
+            PLUGIN_RPC.stderr(&format!("Error in {path:?}: {err}"));
+        }
+    }
+
+    match builder.build() {
+        Ok(ignore) => Some(ignore),
+        Err(err) => {
+            PLUGIN_RPC.stderr(&format!("Failed to build {path:?}: {err}"));
+            None
+        }
+    }
+}
+
+/// Build the user's globs, which are matched against the whole path of a document.
+/// Globs that aren't absolute can match anywhere, so `*.env` matches `.env` files in any directory.
+fn build_globs(globs: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        let pattern = if Path::new(glob).is_absolute() || glob.starts_with("**") {
+            glob.clone()
+        } else {
+            format!("**/{glob}")
+        };
+
+        match GlobBuilder::new(&pattern).literal_separator(true).build() {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(err) => PLUGIN_RPC.stderr(&format!("Bad exclude glob {glob:?}: {err}")),
+        }
+    }
+
+    builder.build().unwrap_or_else(|err| {
+        PLUGIN_RPC.stderr(&format!("Failed to build exclude globs: {err}"));
+        GlobSet::empty()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(path: &str) -> Url {
+        Url::from_file_path(path).unwrap()
+    }
+
+    fn globs(globs: &[&str]) -> Vec<String> {
+        globs.iter().map(|glob| glob.to_string()).collect()
+    }
+
+    #[test]
+    fn relative_globs_match_in_any_directory() {
+        let exclusions = Exclusions::new(None, "", &globs(&["*.env", "secrets/*"]));
+
+        assert!(exclusions.is_excluded(&uri("/project/.env")));
+        assert!(exclusions.is_excluded(&uri("/project/config/prod.env")));
+        assert!(exclusions.is_excluded(&uri("/project/secrets/key.pem")));
+        assert!(!exclusions.is_excluded(&uri("/project/secrets/nested/key.pem")));
+        assert!(!exclusions.is_excluded(&uri("/project/src/env.rs")));
+    }
+
+    #[test]
+    fn absolute_globs_match_from_the_root() {
+        let exclusions = Exclusions::new(None, "", &globs(&["/home/me/work/**"]));
+
+        assert!(exclusions.is_excluded(&uri("/home/me/work/project/main.rs")));
+        assert!(!exclusions.is_excluded(&uri("/home/me/play/main.rs")));
+    }
+
+    #[test]
+    fn bad_globs_are_skipped() {
+        let exclusions = Exclusions::new(None, "", &globs(&["[", "*.key"]));
+
+        assert!(exclusions.is_excluded(&uri("/project/server.key")));
+        assert!(!exclusions.is_excluded(&uri("/project/main.rs")));
+    }
+
+    #[test]
+    fn ignore_file_uses_gitignore_rules() {
+        let root = Path::new("/project");
+        let ignore_file = "# comment\n*.pem\n/build/\nprivate/\n!private/README.md\n";
+        let exclusions = Exclusions::new(Some(root), ignore_file, &[]);
+
+        assert!(exclusions.is_excluded(&uri("/project/certs/server.pem")));
+        assert!(exclusions.is_excluded(&uri("/project/build/out.js")));
+        assert!(!exclusions.is_excluded(&uri("/project/src/build/mod.rs")));
+        assert!(exclusions.is_excluded(&uri("/project/private/notes.md")));
+        assert!(!exclusions.is_excluded(&uri("/project/src/main.rs")));
+        // Only the workspace's own files are covered by its ignore file
+        assert!(!exclusions.is_excluded(&uri("/elsewhere/server.pem")));
+    }
+
+    #[test]
+    fn unreadable_ignore_file_excludes_the_workspace() {
+        let exclusions = Exclusions::unreadable(Path::new("/project"), &[]);
+
+        assert!(exclusions.is_excluded(&uri("/project/src/main.rs")));
+        assert!(!exclusions.is_excluded(&uri("/elsewhere/main.rs")));
+    }
+
+    #[test]
+    fn documents_that_are_not_files_are_never_excluded() {
+        let exclusions = Exclusions::unreadable(Path::new("/"), &globs(&["*"]));
+        let untitled = Url::parse("untitled:Untitled-1").unwrap();
+
+        assert!(!exclusions.is_excluded(&untitled));
+    }
+}
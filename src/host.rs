@@ -0,0 +1,89 @@
+//! Getting at things that are outside of the plugin's sandbox, which only has the volt directory
+//! and the `VOLT_*` environment variables in it. The host gets them for us by running a command.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Result;
+use lapce_plugin::{VoltEnvironment, PLUGIN_RPC};
+
+/// Read the file at `path` on the host, giving an empty string if there is no such file
+pub fn read_file(path: &Path) -> Result<String> {
+    let path = path.to_string_lossy().to_string();
+
+    // A missing file succeeds with nothing, so that only a file that can't be read fails
+    let (program, args) = if VoltEnvironment::operating_system().as_deref() == Ok("windows") {
+        (
+            "cmd",
+            vec![
+                "/C".to_string(),
+                format!("if exist \"{path}\" type \"{path}\""),
+            ],
+        )
+    } else {
+        (
+            "sh",
+            vec![
+                "-c".to_string(),
+                "[ ! -e \"$1\" ] || cat -- \"$1\"".to_string(),
+                "sh".to_string(),
+                path.clone(),
+            ],
+        )
+    };
+
+    let output = PLUGIN_RPC.execute_process(program.to_string(), args)?;
+    if !output.success {
+        let stderr = output.stderr.unwrap_or_default();
+        anyhow::bail!(
+            "Failed to read {path}: {}",
+            String::from_utf8_lossy(&stderr).trim()
+        );
+    }
+
+    let stdout = output.stdout.unwrap_or_default();
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// The environment variables of the host, as the plugin's own environment only has the `VOLT_*`
+/// ones in it
+pub fn env() -> Result<HashMap<String, String>> {
+    let (program, args) = if VoltEnvironment::operating_system().as_deref() == Ok("windows") {
+        ("cmd", vec!["/C".to_string(), "set".to_string()])
+    } else {
+        ("env", Vec::new())
+    };
+
+    let output = PLUGIN_RPC.execute_process(program.to_string(), args)?;
+    if !output.success {
+        anyhow::bail!("Failed to get the environment from {program}");
+    }
+
+    let stdout = output.stdout.unwrap_or_default();
+    Ok(parse_env(&String::from_utf8_lossy(&stdout)))
+}
+
+/// Parse `NAME=value` lines. Values with newlines in them can't be told apart from the lines
+/// that follow, which is fine for the variables that we look at.
+fn parse_env(env: &str) -> HashMap<String, String> {
+    env.lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.trim_end_matches('\r').to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env() {
+        let env =
+            parse_env("HOME=/home/me\r\nHTTPS_PROXY=http://proxy:3128\nEQUALS=a=b\n=C:=C:\\\n");
+
+        assert_eq!(env["HOME"], "/home/me");
+        assert_eq!(env["HTTPS_PROXY"], "http://proxy:3128");
+        assert_eq!(env["EQUALS"], "a=b");
+        assert_eq!(env.len(), 3);
+    }
+}
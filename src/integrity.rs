@@ -0,0 +1,58 @@
+//! Checking that the bundled agent in `dist/` is what we shipped, so that a damaged or half-copied
+//! `dist/` is reported as such rather than as whatever node makes of it.
+
+use anyhow::Result;
+use lapce_plugin::PLUGIN_RPC;
+use sha2::{Digest, Sha256};
+
+/// Checksums of the files in `dist/`, in the format of `sha256sum`.
+/// Regenerated with `cargo make dist-manifest`.
+pub const MANIFEST: &str = "dist.sha256";
+
+/// Check the files in the plugin's `dist/` against the manifest.
+/// The plugin sees the volt directory as its root, so the paths are relative to that.
+pub fn verify_dist() -> Result<()> {
+    let manifest = match std::fs::read_to_string(MANIFEST) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            PLUGIN_RPC.stderr(&format!("Failed to read {MANIFEST}: {err}"));
+            anyhow::bail!(
+                "The Copilot agent can't be checked, as {MANIFEST} is missing. Reinstall the \
+                plugin, or set agent.path to use an agent from elsewhere."
+            );
+        }
+    };
+
+    let mut problems = Vec::new();
+    for line in manifest.lines().filter(|line| !line.trim().is_empty()) {
+        let Some((expected, file)) = line.split_once(char::is_whitespace) else {
+            problems.push(format!("bad manifest line {line:?}"));
+            continue;
+        };
+        // `sha256sum` marks files that were read in binary mode with a `*`
+        let file = file.trim_start().trim_start_matches('*');
+
+        let Ok(content) = std::fs::read(file) else {
+            problems.push(format!("{file} is missing"));
+            continue;
+        };
+
+        let actual = Sha256::digest(&content)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        if !actual.eq_ignore_ascii_case(expected) {
+            problems.push(format!("{file} does not match its checksum"));
+        }
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "The bundled Copilot agent is damaged: {}. Reinstall the plugin, or set \
+            agent.path to use an agent from elsewhere.",
+            problems.join(", ")
+        );
+    }
+
+    Ok(())
+}
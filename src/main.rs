@@ -4,26 +4,36 @@
 #![deny(clippy::print_stdout)]
 #![deny(clippy::print_stderr)]
 
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
 use anyhow::Result;
 use copilot::{
-    CheckAuthStatus, CheckAuthStatusParams, CheckAuthStatusResult, EditorConfiguration, EditorInfo,
-    EditorPluginInfo, GetCompletions, GetCompletionsCycling, GetCompletionsResult, SetEditorInfo,
-    SetEditorInfoParams, SignInConfirm, SignInConfirmParams, SignInConfirmResult, SignInInitiate,
-    SignInInitiateParams, SignInInitiateResult, SignInStatus, Status,
+    CheckAuthStatus, CheckAuthStatusParams, CheckAuthStatusResult, Completion, EditorInfo,
+    EditorPluginInfo, GetCompletions, GetCompletionsCycling, GetCompletionsDoc,
+    GetCompletionsResult, NotifyAccepted, NotifyAcceptedParams, NotifyRejected,
+    NotifyRejectedParams, NotifyShown, NotifyShownParams, PanelSolution, PanelSolutionParams,
+    PanelSolutionsDone, PanelSolutionsDoneParams, SetEditorInfo, SetEditorInfoParams, Status,
 };
 
 use lapce_plugin::{
     lsp::LspRef,
     psp_types::{
         lsp_types::{
-            notification::{DidChangeTextDocument, DidOpenTextDocument},
-            request::{Initialize, InlineCompletionRequest},
-            DidChangeTextDocumentParams, DidOpenTextDocumentParams, DocumentFilter,
-            DocumentSelector, InitializeParams, InitializeResult, InlineCompletionItem,
-            InlineCompletionParams, InlineCompletionResponse, InlineCompletionTriggerKind,
-            InsertTextFormat, MessageType, OneOf, ServerCapabilities, ServerInfo, TextDocumentItem,
+            notification::{
+                DidChangeConfiguration, DidChangeTextDocument, DidCloseTextDocument,
+                DidOpenTextDocument, DidSaveTextDocument,
+            },
+            request::{CodeActionRequest, ExecuteCommand, Initialize, InlineCompletionRequest},
+            CodeActionParams, CodeActionProviderCapability, DidChangeConfigurationParams,
+            DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+            DidSaveTextDocumentParams, DocumentSelector, ExecuteCommandOptions,
+            ExecuteCommandParams, InitializeParams, InitializeResult, InlineCompletionParams,
+            InlineCompletionResponse, InlineCompletionTriggerKind, MessageType, OneOf, Position,
+            SaveOptions, ServerCapabilities, ServerInfo, TextDocumentItem,
             TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
             TextDocumentSyncSaveOptions, Url, VersionedTextDocumentIdentifier,
         },
@@ -34,148 +44,694 @@ use lapce_plugin::{
 
 use serde_json::Value;
 
+use crate::{
+    agent::AgentState,
+    completion::{Cycling, Suggestions, ACCEPT_COMMAND},
+    document::Document,
+    exclude::{Exclusions, IGNORE_FILE},
+    panel::{Panels, ACCEPT_PANEL_SOLUTION_COMMAND, OPEN_PANEL_COMMAND},
+    redact::Redactor,
+    restart::Health,
+    settings::Settings,
+    signin::SignIn,
+};
+
+pub mod agent;
+pub mod completion;
 pub mod copilot;
+pub mod document;
+pub mod editorconfig;
+pub mod exclude;
+pub mod host;
+pub mod integrity;
+pub mod native;
+pub mod panel;
+pub mod proxy;
+pub mod redact;
+pub mod restart;
+pub mod settings;
+pub mod signin;
+pub mod sync;
 
 pub const PLUGIN_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Tab size to give Copilot when neither the project nor the document tells us
+const DEFAULT_TAB_SIZE: u16 = 4;
+
 #[derive(Default)]
 struct State {
     pub lsp: Option<LspRef>,
-    /// Track the latest versions
-    pub versions: HashMap<Url, i32>,
+    /// The root of the workspace that Lapce has open, if any
+    pub workspace_root: Option<PathBuf>,
+    /// Our copies of the open documents, which track their latest versions
+    pub documents: HashMap<Url, Document>,
+    /// Documents that must never be sent to Copilot
+    pub exclusions: Exclusions,
+    /// The documents that we have opened in Copilot, which are those that aren't excluded
+    pub agent_documents: HashSet<Url>,
+    /// Masks secrets in the text of documents before it is sent to Copilot
+    pub redactor: Redactor,
+    /// The uuids of the completions we last gave to Lapce for each document, which have not yet
+    /// been accepted or rejected
+    pub shown: HashMap<Url, Vec<String>>,
+    /// The completions from the last automatic completion for each document
+    pub suggestions: HashMap<Url, Suggestions>,
+    pub settings: Settings,
+    /// The settings as Lapce gave them to us, which are passed on to the agent when starting it
+    pub options: Option<Value>,
+    /// The environment variables of the host, which the plugin isn't given itself
+    pub host_env: HashMap<String, String>,
+    /// The alternatives from the last explicitly invoked completion for each document
+    pub cycling: HashMap<Url, Cycling>,
+    /// The contents of the `.editorconfig` file in each directory that we have looked in, which
+    /// are empty where there is none
+    pub editorconfigs: HashMap<PathBuf, String>,
+    /// The Copilot panel that was last opened for each document
+    pub panels: Panels,
+    /// Id of the last Copilot panel that was opened
+    pub next_panel_id: u64,
+    /// Whether the server supports `textDocument/inlineCompletion`, once we know
+    pub native_completion: Option<bool>,
+    /// Failures of the agent, for restarting it
+    pub health: Health,
+    /// Where the agent is in starting up
+    pub agent_state: AgentState,
+    /// The sign-in that is waiting on the user, if any
+    pub sign_in: Option<SignIn>,
 }
 impl State {
     fn handle_inline_completion(&mut self, id: u64, params: InlineCompletionParams) -> Result<()> {
         PLUGIN_RPC.stderr("Handling Inline Completion");
+        // Without an agent, such as while it is waiting to be restarted, there is nothing to ask
         let Some(lsp) = self.lsp else {
+            PLUGIN_RPC.host_success(id, InlineCompletionResponse::Array(Vec::new()))?;
             return Ok(());
         };
 
+        let uri = &params.text_document_position.text_document.uri;
+
+        // Until the agent is ready it would only give us errors. Lapce is answered before
+        // confirming a sign-in, as that waits on the user.
+        if !self.agent_state.is_ready() {
+            PLUGIN_RPC.host_success(id, InlineCompletionResponse::Array(Vec::new()))?;
+            if params.context.trigger_kind == InlineCompletionTriggerKind::Invoked {
+                self.confirm_sign_in()?;
+            }
+            return Ok(());
+        }
+
+        // Copilot is told about the disabled languages, but we don't rely on it to respect them
+        let disabled = self
+            .documents
+            .get(uri)
+            .is_some_and(|document| self.settings.is_language_disabled(&document.language_id));
+        let refused = self.refuses(uri);
+        let automatic = params.context.trigger_kind == InlineCompletionTriggerKind::Automatic;
+        let unwanted = automatic && !self.settings.editor.enable_auto_completions;
+        if disabled
+            || refused
+            || unwanted
+            || self.exclusions.is_excluded(uri)
+            || self.is_panel_document(uri)
+        {
+            PLUGIN_RPC.host_success(id, InlineCompletionResponse::Array(Vec::new()))?;
+            return Ok(());
+        }
+
+        if self.uses_native_completion() {
+            return self.request_native_completion(lsp, id, params);
+        }
+
         let InlineCompletionParams {
             text_document_position,
             context,
             ..
         } = params;
 
-        let method = match context.trigger_kind {
-            InlineCompletionTriggerKind::Automatic => GetCompletions::METHOD,
-            InlineCompletionTriggerKind::Invoked => GetCompletionsCycling::METHOD,
+        let cycling = match context.trigger_kind {
+            InlineCompletionTriggerKind::Automatic => false,
+            InlineCompletionTriggerKind::Invoked => true,
             _ => {
                 PLUGIN_RPC.stderr(&format!(
                     "Unsupported trigger kind: {:?}",
                     context.trigger_kind
                 ));
+                PLUGIN_RPC.host_success(id, InlineCompletionResponse::Array(Vec::new()))?;
                 return Ok(());
             }
         };
 
-        let version = self
-            .versions
-            .get(&text_document_position.text_document.uri)
-            .copied()
-            .unwrap_or_else(|| {
-                PLUGIN_RPC.stderr(&format!(
-                    "No version for uri: {:?}",
-                    text_document_position.text_document.uri
-                ));
-                0
-            });
+        let uri = &text_document_position.text_document.uri;
+        let position = text_document_position.position;
 
-        let params = copilot::GetCompletionsParams {
-            doc: copilot::GetCompletionsDoc {
-                position: text_document_position.position,
-                uri: text_document_position.text_document.uri.clone(),
-                version,
-                insert_spaces: None,
-                tab_size: None,
-                source: None,
-                language_id: None,
-                relative_path: None,
-                if_inserted: None,
-            },
-            options: None,
+        // If the user is typing what we already suggested, then the rest of the suggestion is
+        // still good and we don't need to ask Copilot again
+        if !cycling {
+            if let Some(completions) = self.continue_suggestions(uri, position) {
+                return self.reply_completions(id, uri.clone(), completions);
+            }
+        }
+
+        // Invoking completion again where we already have the alternatives steps through them.
+        // They are all still on offer, so none of them have been rejected yet.
+        let version = self.documents.get(uri).map(|d| d.version);
+        if let Some(cached) = self.cycling.get_mut(uri) {
+            if cycling && version.is_some_and(|version| cached.is_for(version, position)) {
+                let completions = cached.cycle();
+                let uuids: Vec<String> = completions.iter().map(|c| c.uuid.clone()).collect();
+                if let Err(err) = self.reject_shown(lsp, uri, &uuids) {
+                    PLUGIN_RPC.stderr(&format!("Failed to notify copilot of rejection: {err}"));
+                }
+
+                return self.reply_completions(id, uri.clone(), completions);
+            }
+        }
+
+        // A new request means that whatever we suggested previously for this document is no
+        // longer being displayed, so if it wasn't accepted then it was rejected
+        if let Err(err) = self.reject_shown(lsp, uri, &[]) {
+            PLUGIN_RPC.stderr(&format!("Failed to notify copilot of rejection: {err}"));
+        }
+
+        self.request_completions(lsp, id, uri.clone(), position, cycling)
+    }
+
+    /// Ask Copilot for completions, and give them to Lapce as the reply to its inline completion
+    /// request `host_id`.  
+    /// The plugin api only has blocking requests, so nothing else is handled until Copilot
+    /// replies.
+    fn request_completions(
+        &mut self,
+        lsp: LspRef,
+        host_id: u64,
+        uri: Url,
+        position: Position,
+        cycling: bool,
+    ) -> Result<()> {
+        let method = if cycling {
+            GetCompletionsCycling::METHOD
+        } else {
+            GetCompletions::METHOD
         };
 
+        let Some(doc) = self.completions_doc(&uri, position) else {
+            PLUGIN_RPC.stderr(&format!("No document for uri: {uri:?}"));
+            PLUGIN_RPC.host_success(host_id, InlineCompletionResponse::Array(Vec::new()))?;
+            return Ok(());
+        };
+        let version = doc.version;
+
+        let params = copilot::GetCompletionsParams { doc, options: None };
+
         let params = serde_json::to_value(params).unwrap();
 
         PLUGIN_RPC.stderr(&format!(
-            "URI: {:?}; VERSION: {:?}; sending to lsp",
-            &text_document_position.text_document.uri, version
+            "URI: {uri:?}; VERSION: {version:?}; sending to lsp"
         ));
-        let GetCompletionsResult { completions } = lsp.send_request_blocking(method, params)?;
+        let result = self.request_agent(lsp, method, params);
+
+        let completions = self.parse_completions(result)?;
+        let completions = self.unmask_completions(&uri, completions);
+        if cycling {
+            self.cycling.insert(
+                uri.clone(),
+                Cycling::new(version, position, completions.clone()),
+            );
+        } else {
+            self.cache_suggestions(&uri, version, position, &completions);
+        }
+
+        self.reply_completions(host_id, uri, completions)
+    }
+
+    /// Describe the document and position to Copilot
+    fn completions_doc(&self, uri: &Url, position: Position) -> Option<GetCompletionsDoc> {
+        let document = self.documents.get(uri)?;
+        let indentation = document.indentation();
 
-        PLUGIN_RPC.stderr(&format!("Got completions: {completions:?}"));
+        Some(GetCompletionsDoc {
+            position,
+            uri: uri.clone(),
+            version: document.version,
+            insert_spaces: Some(indentation.insert_spaces.unwrap_or(true)),
+            tab_size: Some(indentation.tab_size.unwrap_or(DEFAULT_TAB_SIZE)),
+            source: self.redacted_text(uri).map(Cow::into_owned),
+            language_id: Some(document.language_id.clone()),
+            relative_path: self.relative_path(uri),
+            if_inserted: None,
+        })
+    }
+
+    /// Send a request to Copilot that we don't need anything back from.  
+    /// Its reply is only waited on because the plugin api has no other way of sending requests,
+    /// and a failure is logged rather than stopping whatever we were doing.
+    fn send_telemetry<R: Request>(&mut self, lsp: LspRef, params: R::Params) -> Result<()> {
+        if let Err(err) = self.request_agent(lsp, R::METHOD, params) {
+            PLUGIN_RPC.stderr(&format!("Copilot {} failed: {err}", R::METHOD));
+        }
+
+        Ok(())
+    }
+
+    /// Get the rest of the last automatic completions for the document, if what has been typed
+    /// since is the start of them.
+    fn continue_suggestions(&mut self, uri: &Url, position: Position) -> Option<Vec<Completion>> {
+        let document = self.documents.get(uri)?;
+        let suggestions = self.suggestions.get(uri)?;
+        let (before, after) = document.split_line_at(position)?;
+
+        let completions = suggestions.continue_at(position, before, after);
+        if completions.is_empty() {
+            // The user has gone their own way, so the suggestions won't be useful again
+            self.suggestions.remove(uri);
+            return None;
+        }
+
+        Some(completions)
+    }
+
+    /// Remember the completions that Copilot gave for `position`, so long as the document hasn't
+    /// changed since they were asked for.
+    fn cache_suggestions(
+        &mut self,
+        uri: &Url,
+        version: i32,
+        position: Position,
+        completions: &[Completion],
+    ) {
+        let Some(document) = self.documents.get(uri) else {
+            return;
+        };
+        if document.version != version || completions.is_empty() {
+            self.suggestions.remove(uri);
+            return;
+        }
+        let Some((before, after)) = document.split_line_at(position) else {
+            return;
+        };
+
+        self.suggestions.insert(
+            uri.clone(),
+            Suggestions {
+                position,
+                before: before.to_string(),
+                after: after.to_string(),
+                completions: completions.to_vec(),
+            },
+        );
+    }
+
+    /// Get the completions out of Copilot's reply to a completion request
+    fn parse_completions(&self, result: Result<Value>) -> Result<Vec<Completion>> {
+        match result {
+            Ok(result) => {
+                let GetCompletionsResult { completions } = serde_json::from_value(result)?;
+                PLUGIN_RPC.stderr(&format!("Got completions: {completions:?}"));
+
+                Ok(completions)
+            }
+            Err(err) => {
+                PLUGIN_RPC.stderr(&format!("Copilot failed to get completions: {err}"));
+
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Give completions to Lapce as the reply to its inline completion request `host_id`.
+    fn reply_completions(
+        &mut self,
+        host_id: u64,
+        uri: Url,
+        completions: Vec<Completion>,
+    ) -> Result<()> {
+        self.shown.insert(
+            uri.clone(),
+            completions.iter().map(|c| c.uuid.clone()).collect(),
+        );
 
         let completions: Vec<_> = completions
             .into_iter()
-            .map(|c| {
-                InlineCompletionItem {
-                    // TODO: is this correct?
-                    insert_text: c.display_text,
-                    insert_text_format: Some(InsertTextFormat::PLAIN_TEXT),
-                    filter_text: None,
-                    range: None, // TODO
-                    command: None,
-                }
-            })
+            .map(|c| completion::to_inline_completion_item(&uri, c))
             .collect();
         let params = InlineCompletionResponse::Array(completions);
         let params = serde_json::to_value(params).unwrap();
 
         PLUGIN_RPC.stderr("Sending success");
-        PLUGIN_RPC.host_success(id, params)?;
+        PLUGIN_RPC.host_success(host_id, params)?;
+
+        Ok(())
+    }
+
+    /// Get the path of the document relative to the workspace root
+    fn relative_path(&self, uri: &Url) -> Option<String> {
+        let root = self.workspace_root.as_ref()?;
+        let path = uri.to_file_path().ok()?;
+        let relative = path.strip_prefix(root).ok()?;
+
+        Some(relative.to_string_lossy().replace('\\', "/"))
+    }
+
+    /// Tell Copilot that the completions that we gave for `uri` were shown but not accepted,
+    /// other than those in `kept`, which are still on offer.
+    fn reject_shown(&mut self, lsp: LspRef, uri: &Url, kept: &[String]) -> Result<()> {
+        let Some(shown) = self.shown.remove(uri) else {
+            return Ok(());
+        };
+
+        let uuids: Vec<String> = shown
+            .iter()
+            .filter(|uuid| !kept.contains(uuid))
+            .cloned()
+            .collect();
+        if uuids.is_empty() {
+            return Ok(());
+        }
+
+        // Lapce only displays the first completion
+        if let Some(uuid) = shown.first().filter(|uuid| uuids.contains(uuid)) {
+            self.send_telemetry::<NotifyShown>(
+                lsp,
+                NotifyShownParams {
+                    uuid: uuid.clone(),
+                    options: None,
+                },
+            )?;
+        }
+        self.send_telemetry::<NotifyRejected>(
+            lsp,
+            NotifyRejectedParams {
+                uuids,
+                options: None,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    fn handle_execute_command(&mut self, id: u64, params: ExecuteCommandParams) -> Result<()> {
+        PLUGIN_RPC.host_success(id, Value::Null)?;
+
+        let ours = [
+            ACCEPT_COMMAND,
+            OPEN_PANEL_COMMAND,
+            ACCEPT_PANEL_SOLUTION_COMMAND,
+        ];
+        if !ours.contains(&params.command.as_str()) {
+            return self.forward_command(params);
+        }
+
+        // All of our commands are for a document, with one more argument
+        let ExecuteCommandParams {
+            command, arguments, ..
+        } = params;
+        let (Some(uri), Some(arg)) = (
+            arguments
+                .first()
+                .and_then(Value::as_str)
+                .and_then(|uri| Url::parse(uri).ok()),
+            arguments.get(1),
+        ) else {
+            anyhow::bail!("Bad arguments to {command}: {arguments:?}");
+        };
+
+        match command.as_str() {
+            ACCEPT_COMMAND => {
+                let Some(lsp) = self.lsp else {
+                    return Ok(());
+                };
+                let Some(uuid) = arg.as_str() else {
+                    anyhow::bail!("Bad arguments to {command}: {arguments:?}");
+                };
+
+                if self
+                    .shown
+                    .get(&uri)
+                    .is_some_and(|uuids| uuids.iter().any(|u| u == uuid))
+                {
+                    self.shown.remove(&uri);
+                }
+
+                self.send_telemetry::<NotifyAccepted>(
+                    lsp,
+                    NotifyAcceptedParams {
+                        uuid: uuid.to_string(),
+                        options: None,
+                    },
+                )?;
+            }
+            OPEN_PANEL_COMMAND => {
+                let position: Position = serde_json::from_value(arg.clone())?;
+
+                self.open_panel(uri, position)?;
+            }
+            ACCEPT_PANEL_SOLUTION_COMMAND => {
+                let Some(solution_id) = arg.as_str() else {
+                    anyhow::bail!("Bad arguments to {command}: {arguments:?}");
+                };
+
+                self.accept_panel_solution(uri, solution_id)?;
+            }
+            command => {
+                PLUGIN_RPC.stderr(&format!("Unknown command: {command:?}"));
+            }
+        }
 
         Ok(())
     }
 
     fn handle_did_change_text_document(&mut self, params: DidChangeTextDocumentParams) {
-        let DidChangeTextDocumentParams { text_document, .. } = params;
+        let DidChangeTextDocumentParams {
+            text_document,
+            content_changes,
+        } = params;
 
         let VersionedTextDocumentIdentifier { uri, version } = text_document;
 
-        self.versions.insert(uri, version);
+        // Whether Copilot's copy of the document has masks in it, which the change may move
+        let had_secrets = self.agent_documents.contains(&uri) && !self.secrets(&uri).is_empty();
+
+        let Some(document) = self.documents.get_mut(&uri) else {
+            PLUGIN_RPC.stderr(&format!("Change for document that was not opened: {uri:?}"));
+            return;
+        };
+
+        for change in &content_changes {
+            document.apply_change(change.clone());
+        }
+        document.version = version;
+
+        // The alternatives are only for the version they were generated for
+        self.cycling.remove(&uri);
+
+        if let Err(err) = self.forward_change(&uri, content_changes, had_secrets) {
+            PLUGIN_RPC.stderr(&format!("Failed to send change to copilot: {err}"));
+        }
     }
 
     fn handle_did_open_text_document(&mut self, params: DidOpenTextDocumentParams) {
         let DidOpenTextDocumentParams { text_document, .. } = params;
 
-        let TextDocumentItem { uri, version, .. } = text_document;
+        let TextDocumentItem {
+            uri,
+            language_id,
+            version,
+            text,
+        } = text_document;
 
-        self.versions.insert(uri, version);
+        let mut document = Document::new(language_id, version, text);
+        if let Ok(path) = uri.to_file_path() {
+            document.configured_indentation = self.configured_indentation(&path);
+        }
+
+        self.documents.insert(uri.clone(), document);
+
+        if let Err(err) = self.sync_agent_document(&uri) {
+            PLUGIN_RPC.stderr(&format!("Failed to open document in copilot: {err}"));
+        }
+    }
+
+    fn handle_did_close_text_document(&mut self, params: DidCloseTextDocumentParams) {
+        let DidCloseTextDocumentParams { text_document } = params;
+        let uri = text_document.uri;
+
+        if let Some(lsp) = self.lsp {
+            if let Err(err) = self.reject_shown(lsp, &uri, &[]) {
+                PLUGIN_RPC.stderr(&format!("Failed to notify copilot of rejection: {err}"));
+            }
+        }
+
+        if let Err(err) = self.close_agent_document(&uri) {
+            PLUGIN_RPC.stderr(&format!("Failed to close document in copilot: {err}"));
+        }
+
+        // Forget everything we know about the document, so that we don't keep growing over a long
+        // session
+        self.documents.remove(&uri);
+        self.shown.remove(&uri);
+        self.suggestions.remove(&uri);
+        self.cycling.remove(&uri);
+        if let Some(panel) = self.panels.remove(&uri) {
+            panel.remove();
+        }
+        self.evict_editorconfigs();
+    }
+
+    fn handle_did_save_text_document(&mut self, params: DidSaveTextDocumentParams) {
+        let DidSaveTextDocumentParams {
+            text_document,
+            text,
+        } = params;
+        let uri = text_document.uri;
+
+        let Ok(path) = uri.to_file_path() else {
+            return;
+        };
+
+        // Saving an `.editorconfig` can change the indentation of any open document
+        if path.file_name().is_some_and(|name| name == ".editorconfig") {
+            if let Some(dir) = path.parent() {
+                self.editorconfigs.remove(dir);
+            }
+
+            let uris: Vec<Url> = self.documents.keys().cloned().collect();
+            for uri in uris {
+                if let Ok(path) = uri.to_file_path() {
+                    let indentation = self.configured_indentation(&path);
+                    if let Some(document) = self.documents.get_mut(&uri) {
+                        document.configured_indentation = indentation;
+                    }
+                }
+            }
+        }
+
+        // Changing what is excluded can mean that documents have to be opened or closed in Copilot
+        if self
+            .workspace_root
+            .as_ref()
+            .is_some_and(|root| root.join(IGNORE_FILE) == path)
+        {
+            self.load_exclusions();
+            self.sync_agent_documents();
+        }
+
+        let indentation = self.configured_indentation(&path);
+        let Some(document) = self.documents.get_mut(&uri) else {
+            return;
+        };
+
+        // The saved text is the truth, in case we ever got out of sync with Lapce
+        if let Some(text) = text {
+            document.text = text;
+        }
+        document.configured_indentation = indentation;
+    }
+
+    fn handle_did_change_configuration(&mut self, params: DidChangeConfigurationParams) {
+        let settings = Settings::from_options(Some(&params.settings));
+        if settings == self.settings {
+            return;
+        }
+
+        let old = std::mem::replace(&mut self.settings, settings);
+        self.options = Some(params.settings);
+
+        if old.redact != self.settings.redact {
+            self.set_redactor(Redactor::new(&self.settings.redact));
+        }
+
+        if old.exclude != self.settings.exclude {
+            self.load_exclusions();
+            self.sync_agent_documents();
+        }
+
+        // The agent has to be started again for a different command to take effect, which is
+        // also how one that failed to start with the old settings is given another try.
+        // Changing where we sign in to also means signing in again, which happens on start.
+        if old.node_path() != self.settings.node_path()
+            || old.agent != self.settings.agent
+            || old.auth != self.settings.auth
+        {
+            if let Err(err) = self.restart_agent() {
+                PLUGIN_RPC.stderr(&format!("Failed to restart copilot: {err}"));
+                let _ = PLUGIN_RPC.window_show_message(
+                    MessageType::ERROR,
+                    format!("Failed to restart Copilot: {err}"),
+                );
+            }
+            return;
+        }
+
+        let Some(lsp) = self.lsp else {
+            return;
+        };
+        if old.editor != self.settings.editor || old.proxy != self.settings.proxy {
+            if let Err(err) = send_editor_info(lsp, &self.settings, &self.host_env) {
+                PLUGIN_RPC.stderr(&format!("Failed to update copilot configuration: {err}"));
+            }
+        }
     }
 }
 
 register_plugin!(State);
 
 // TODO: Icon in corner for when copilot is doing stuff and whether it is active
-// TODO: Copilot generation panel
 // TODO: Swap between generations, though that's a Lapce thing
 // TODO: Copilot Chat support
 // TODO: the other copilot stuff
 fn initialize(state: &mut State, params: InitializeParams) -> Result<()> {
     PLUGIN_RPC.window_log_message(MessageType::ERROR, "Initializing copilot".to_string())?;
-    let document_selector: DocumentSelector = vec![DocumentFilter {
-        language: None,
-        pattern: Some(String::from("**/*")),
-        scheme: None,
-    }];
 
-    // By default we just try using some global node
-    let mut node_url = Url::parse("urn:node")?;
-    let mut node_path = "node";
-    if let Some(options) = params.initialization_options.as_ref() {
-        if let Some(node) = options.get("node") {
-            if let Some(path) = node.get("path") {
-                if let Some(path) = path.as_str() {
-                    node_url = Url::parse(&format!("urn:{}", path))?;
-                    node_path = path;
-                }
-            }
-        }
+    #[allow(deprecated)]
+    let root_uri = params
+        .workspace_folders
+        .as_ref()
+        .and_then(|folders| folders.first())
+        .map(|folder| &folder.uri)
+        .or(params.root_uri.as_ref());
+    state.workspace_root = root_uri.and_then(|uri| uri.to_file_path().ok());
+
+    state.settings = Settings::from_options(params.initialization_options.as_ref());
+    state.options = params.initialization_options;
+    state.redactor = Redactor::new(&state.settings.redact);
+    state.load_exclusions();
+    state.host_env = host::env().unwrap_or_else(|err| {
+        PLUGIN_RPC.stderr(&format!("{err}"));
+        HashMap::new()
+    });
+
+    start_agent(state)
+}
+
+/// Start the Copilot agent with the current settings, then tell it about the editor and make
+/// sure that the user is signed in.
+pub(crate) fn start_agent(state: &mut State) -> Result<()> {
+    state.set_agent_state(AgentState::Starting)?;
+
+    let result = handshake(state);
+    if let Err(err) = &result {
+        state.set_agent_state(AgentState::Failed(err.to_string()))?;
+    }
+
+    result
+}
+
+fn handshake(state: &mut State) -> Result<()> {
+    // Lapce isn't given any documents to send to Copilot itself, as we forward them so that
+    // excluded documents never reach it
+    let document_selector: DocumentSelector = Vec::new();
+
+    // A custom agent is the user's to look after
+    if state.settings.agent_path().is_none() {
+        integrity::verify_dist()?;
     }
 
-    if !check_node_version(node_path.to_string())? {
+    let command = agent_command(&state.settings)?;
+
+    // By default we just try using some global node
+    if command.node && !check_node_version(state.settings.node_path().to_string())? {
         PLUGIN_RPC.stderr("NODE VERSION WAS BAD OR SOMETHING?");
+        state.set_agent_state(AgentState::Failed("Unusable node".to_string()))?;
         return Ok(());
     }
 
@@ -184,49 +740,37 @@ fn initialize(state: &mut State, params: InitializeParams) -> Result<()> {
         "Everything was fine. Starting LSP".to_string(),
     )?;
 
-    let volt_uri = std::env::var("VOLT_URI")?;
-    let volt_uri = volt_uri.strip_prefix("file://").unwrap_or(&volt_uri);
-    let file_name = "dist/agent.js";
-    let agent_path = std::path::Path::new(&volt_uri).join(file_name);
-    let args = vec![agent_path.to_string_lossy().to_string()];
-
+    let program_url = Url::parse(&format!("urn:{}", command.program))?;
     let lsp = PLUGIN_RPC.start_lsp(
-        node_url,
-        args,
+        program_url,
+        command.args,
         document_selector,
-        params.initialization_options,
+        state.options.clone(),
     )?;
 
     state.lsp = Some(lsp);
 
-    let resp: String = lsp.send_request_blocking(
-        SetEditorInfo::METHOD,
-        SetEditorInfoParams {
-            editor_info: EditorInfo {
-                name: "Lapce".to_string(),
-                version: "0.3.1".to_string(),
-            },
-            editor_plugin_info: EditorPluginInfo {
-                name: "lapce-copilot".to_string(),
-                version: PLUGIN_VERSION.to_string(),
-            },
-            editor_configuration: Some(EditorConfiguration {
-                show_editor_completions: Some(true),
-                enable_auto_completions: Some(true),
-                disabled_languages: Some(vec![]),
-                ..Default::default()
-            }),
-            auth_provider: None,
-            network_proxy: None,
-            options: None,
-        },
-    )?;
+    // Starting the agent only queues it up, so it isn't known to be running until it answers.
+    // One that we refuse is stopped rather than left running without being used.
+    let answered = match agent::check_agent_version(lsp) {
+        Ok(answered) => answered,
+        Err(err) => {
+            state.drop_agent(true)?;
+            return Err(err);
+        }
+    };
+    if answered {
+        state.set_agent_state(AgentState::Initialized)?;
+    }
 
-    if resp != "OK" {
-        PLUGIN_RPC.stderr(&format!(
-            "RESPONSE TO Copilot's setEditorInfo WAS NOT OK: {resp:?}"
-        ));
+    send_editor_info(lsp, &state.settings, &state.host_env)?;
+    if state.agent_state == AgentState::Starting {
+        state.set_agent_state(AgentState::Initialized)?;
     }
+    state.set_agent_state(AgentState::Configured)?;
+
+    state.agent_documents.clear();
+    state.sync_agent_documents();
 
     let status: CheckAuthStatusResult = lsp.send_request_blocking(
         CheckAuthStatus::METHOD,
@@ -236,6 +780,7 @@ fn initialize(state: &mut State, params: InitializeParams) -> Result<()> {
     if status.status == Status::Ok {
         PLUGIN_RPC
             .window_log_message(MessageType::INFO, "Copilot already signed in".to_string())?;
+        state.set_agent_state(AgentState::Ready)?;
         return Ok(());
 
         // // Sign out for testing
@@ -250,44 +795,129 @@ fn initialize(state: &mut State, params: InitializeParams) -> Result<()> {
         // PLUGIN_RPC.stderr(&format!("AUTH STATUS NEW: {status:?}"));
     }
 
-    // Log in
-    let resp: SignInInitiateResult =
-        lsp.send_request_blocking(SignInInitiate::METHOD, SignInInitiateParams {})?;
+    // Log in, which is finished once the user has entered the code
+    state.start_sign_in(lsp)
+}
+
+/// How to run the agent
+struct AgentCommand {
+    program: String,
+    args: Vec<String>,
+    /// Whether the agent is a script that is run with node
+    node: bool,
+}
+
+/// The command for running the agent that the settings ask for, which by default is the bundled
+/// `dist/agent.js`
+fn agent_command(settings: &Settings) -> Result<AgentCommand> {
+    let agent_path = match settings.agent_path() {
+        Some(path) => PathBuf::from(path),
+        None => volt_dir()?.join("dist/agent.js"),
+    };
+    let node = agent_path
+        .extension()
+        .is_some_and(|ext| ext == "js" || ext == "cjs" || ext == "mjs");
+    let agent_path = agent_path.to_string_lossy().to_string();
+
+    let (mut program, mut args) = if node {
+        (settings.node_path().to_string(), vec![agent_path])
+    } else {
+        (agent_path, Vec::new())
+    };
+    args.extend(settings.agent.args.iter().cloned());
 
-    match resp.status {
-        SignInStatus::AlreadySignedIn => {
-            PLUGIN_RPC.window_log_message(
+    // Anything else would be taken by `env` as an option or as the program to run
+    let (env, invalid): (Vec<&String>, Vec<&String>) = settings
+        .agent
+        .env
+        .iter()
+        .partition(|entry| is_env_assignment(entry));
+    if !invalid.is_empty() {
+        PLUGIN_RPC.window_show_message(
+            MessageType::WARNING,
+            format!("Ignoring agent.env entries that are not NAME=value: {invalid:?}"),
+        )?;
+    }
+
+    // Lapce can't set the environment of a language server, so we go through `env` to do it
+    if !env.is_empty() {
+        if VoltEnvironment::operating_system().as_deref() == Ok("windows") {
+            PLUGIN_RPC.window_show_message(
                 MessageType::WARNING,
-                "Already signed-in despite checking that..".to_string(),
+                "agent.env is not supported on Windows, so it is ignored".to_string(),
             )?;
-            return Ok(());
-        }
-        SignInStatus::PromptUserDeviceFlow => {
-            let Some(verification_uri) = &resp.verification_uri else {
-                PLUGIN_RPC
-                    .window_log_message(MessageType::ERROR, "No verification uri".to_string())?;
-                anyhow::bail!("No verification uri: {resp:?}");
-            };
-            let Some(user_code) = &resp.user_code else {
-                PLUGIN_RPC.window_log_message(
-                    MessageType::ERROR,
-                    "No user code for sign-in".to_string(),
-                )?;
-                anyhow::bail!("No user code: {resp:?}");
-            };
-            let message = format!("Input this code in the opened browser: {}", user_code);
-            PLUGIN_RPC.window_show_message(MessageType::INFO, message)?;
-
-            open(verification_uri)?;
+        } else {
+            args = env
+                .into_iter()
+                .cloned()
+                .chain([program])
+                .chain(args)
+                .collect();
+            program = "env".to_string();
         }
     }
 
-    let _resp: SignInConfirmResult =
-        lsp.send_request_blocking(SignInConfirm::METHOD, SignInConfirmParams {})?;
+    Ok(AgentCommand {
+        program,
+        args,
+        node,
+    })
+}
+
+/// Whether `entry` is a `NAME=value` assignment of an environment variable
+fn is_env_assignment(entry: &str) -> bool {
+    let Some((name, _)) = entry.split_once('=') else {
+        return false;
+    };
+
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Tell Copilot about the editor and its configuration
+fn send_editor_info(
+    lsp: LspRef,
+    settings: &Settings,
+    host_env: &HashMap<String, String>,
+) -> Result<()> {
+    let resp: String = lsp.send_request_blocking(
+        SetEditorInfo::METHOD,
+        SetEditorInfoParams {
+            editor_info: EditorInfo {
+                name: "Lapce".to_string(),
+                version: "0.3.1".to_string(),
+            },
+            editor_plugin_info: EditorPluginInfo {
+                name: "lapce-copilot".to_string(),
+                version: PLUGIN_VERSION.to_string(),
+            },
+            editor_configuration: Some(settings.editor_configuration()),
+            auth_provider: settings.auth_provider(),
+            network_proxy: proxy::network_proxy(settings, host_env),
+            options: None,
+        },
+    )?;
+
+    if resp != "OK" {
+        PLUGIN_RPC.stderr(&format!(
+            "RESPONSE TO Copilot's setEditorInfo WAS NOT OK: {resp:?}"
+        ));
+    }
 
     Ok(())
 }
 
+/// The directory that the plugin is installed in
+fn volt_dir() -> Result<PathBuf> {
+    let volt_uri = std::env::var("VOLT_URI")?;
+    let volt_uri = volt_uri.strip_prefix("file://").unwrap_or(&volt_uri);
+
+    Ok(PathBuf::from(volt_uri))
+}
+
 fn open(url: &str) -> anyhow::Result<()> {
     let os = VoltEnvironment::operating_system()?;
     match os.as_str() {
@@ -322,20 +952,32 @@ fn open(url: &str) -> anyhow::Result<()> {
 // completions. The plugin then translates back and forth between the inline completion request and
 // the custom request that copilot expects.
 //
-// Once Copilot properly supports inlineCompletion requests, this extra code can be removed.
+// The Copilot language server does support inlineCompletion requests, and with it the requests are
+// passed straight through (see `native.rs`). The translation is kept for older agent.js versions.
 
 /// Reply to the initialize request properly
 fn reply_initialize(id: u64, _params: &InitializeParams) -> Result<()> {
     let message = InitializeResult {
         capabilities: ServerCapabilities {
             inline_completion_provider: Some(OneOf::Left(true)),
-            // We don't care about the file contents, but we need to be alerted so that we can
-            // track the version number for copilot.
+            code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: vec![
+                    ACCEPT_COMMAND.to_string(),
+                    OPEN_PANEL_COMMAND.to_string(),
+                    ACCEPT_PANEL_SOLUTION_COMMAND.to_string(),
+                ],
+                ..Default::default()
+            }),
+            // We keep our own copy of the file contents, as Copilot wants the version number and
+            // text of the document, which `textDocument/inlineCompletion` does not include.
             text_document_sync: Some(TextDocumentSyncCapability::Options(
                 TextDocumentSyncOptions {
                     change: Some(TextDocumentSyncKind::INCREMENTAL),
                     open_close: Some(true),
-                    save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                    save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                        include_text: Some(true),
+                    })),
                     ..Default::default()
                 },
             )),
@@ -355,6 +997,9 @@ fn reply_initialize(id: u64, _params: &InitializeParams) -> Result<()> {
 
 impl LapcePlugin for State {
     fn handle_request(&mut self, id: u64, method: String, params: Value) {
+        self.restart_if_due();
+        self.check_sign_in();
+
         match method.as_str() {
             Initialize::METHOD => {
                 let params: InitializeParams = serde_json::from_value(params).unwrap();
@@ -388,11 +1033,43 @@ impl LapcePlugin for State {
                     PLUGIN_RPC.stderr(&format!("copilot inline completion error: {e}"));
                 }
             }
+            CodeActionRequest::METHOD => {
+                let params: CodeActionParams = match serde_json::from_value(params) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        PLUGIN_RPC.stderr(&format!("Failed to parse code action params: {err}"));
+                        return;
+                    }
+                };
+
+                let actions =
+                    self.panel_code_actions(&params.text_document.uri, params.range.start);
+                if let Err(e) = PLUGIN_RPC.host_success(id, actions) {
+                    PLUGIN_RPC.stderr(&format!("copilot code action error: {e}"));
+                }
+            }
+            ExecuteCommand::METHOD => {
+                let params: ExecuteCommandParams = match serde_json::from_value(params) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        PLUGIN_RPC
+                            .stderr(&format!("Failed to parse execute command params: {err}"));
+                        return;
+                    }
+                };
+
+                if let Err(e) = self.handle_execute_command(id, params) {
+                    PLUGIN_RPC.stderr(&format!("copilot execute command error: {e}"));
+                }
+            }
             _ => {}
         }
     }
 
     fn handle_notification(&mut self, method: String, params: Value) {
+        self.restart_if_due();
+        self.check_sign_in();
+
         match method.as_str() {
             DidChangeTextDocument::METHOD => {
                 let params: DidChangeTextDocumentParams = serde_json::from_value(params).unwrap();
@@ -404,6 +1081,51 @@ impl LapcePlugin for State {
 
                 self.handle_did_open_text_document(params);
             }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(params).unwrap();
+
+                self.handle_did_close_text_document(params);
+            }
+            DidSaveTextDocument::METHOD => {
+                let params: DidSaveTextDocumentParams = serde_json::from_value(params).unwrap();
+
+                self.handle_did_save_text_document(params);
+            }
+            DidChangeConfiguration::METHOD => {
+                let params: DidChangeConfigurationParams = serde_json::from_value(params).unwrap();
+
+                self.handle_did_change_configuration(params);
+            }
+            PanelSolution::METHOD => {
+                let params: Result<PanelSolutionParams, _> = serde_json::from_value(params);
+                let params = match params {
+                    Ok(params) => params,
+                    Err(err) => {
+                        PLUGIN_RPC.stderr(&format!("Failed to parse panel solution params: {err}"));
+                        return;
+                    }
+                };
+
+                if let Err(e) = self.handle_panel_solution(params) {
+                    PLUGIN_RPC.stderr(&format!("copilot panel error: {e}"));
+                }
+            }
+            PanelSolutionsDone::METHOD => {
+                let params: Result<PanelSolutionsDoneParams, _> = serde_json::from_value(params);
+                let params = match params {
+                    Ok(params) => params,
+                    Err(err) => {
+                        PLUGIN_RPC.stderr(&format!(
+                            "Failed to parse panel solutions done params: {err}"
+                        ));
+                        return;
+                    }
+                };
+
+                if let Err(e) = self.handle_panel_solutions_done(params) {
+                    PLUGIN_RPC.stderr(&format!("copilot panel error: {e}"));
+                }
+            }
             _ => {}
         }
     }
@@ -468,3 +1190,96 @@ fn check_node_version(node: String) -> Result<bool> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use lapce_plugin::psp_types::lsp_types::TextDocumentIdentifier;
+
+    use super::*;
+    use crate::panel::Panel;
+
+    /// Open `uri` in `state`, with `text` as its contents
+    fn open(state: &mut State, uri: &Url, text: &str) {
+        state.handle_did_open_text_document(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: uri.clone(),
+                language_id: "rust".to_string(),
+                version: 1,
+                text: text.to_string(),
+            },
+        });
+    }
+
+    #[test]
+    fn closed_documents_are_forgotten() {
+        let mut state = State {
+            workspace_root: Some(PathBuf::from("/project")),
+            ..Default::default()
+        };
+
+        // The .editorconfig files were already looked for, so the host isn't asked for them
+        for dir in ["/project", "/project/src"] {
+            state
+                .editorconfigs
+                .insert(PathBuf::from(dir), String::new());
+        }
+        let lib = Url::from_file_path("/project/src/lib.rs").unwrap();
+        open(&mut state, &lib, "mod module;\n");
+
+        // A long session of documents being opened, completed in and closed
+        for i in 0..1000 {
+            let dir = PathBuf::from(format!("/project/module-{i}"));
+            state.editorconfigs.insert(dir.clone(), String::new());
+            let uri = Url::from_file_path(dir.join("main.rs")).unwrap();
+            open(&mut state, &uri, "fn main() {}\n");
+
+            let position = Position::new(0, 3);
+            state.shown.insert(uri.clone(), vec![format!("uuid-{i}")]);
+            state
+                .cycling
+                .insert(uri.clone(), Cycling::new(1, position, Vec::new()));
+            state.suggestions.insert(
+                uri.clone(),
+                Suggestions {
+                    position,
+                    before: "fn ".to_string(),
+                    after: "main() {}".to_string(),
+                    completions: Vec::new(),
+                },
+            );
+            state.panels.insert(
+                uri.clone(),
+                Panel {
+                    id: i.to_string(),
+                    version: 1,
+                    position,
+                    language_id: "rust".to_string(),
+                    relative_path: format!("module-{i}/main.rs"),
+                    path: PathBuf::from(format!("/volt/panels/{i}-main.rs.md")),
+                    file: PathBuf::from(format!("panels/{i}-main.rs.md")),
+                    target: None,
+                    solutions: Vec::new(),
+                    done: None,
+                },
+            );
+
+            state.handle_did_close_text_document(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri },
+            });
+        }
+
+        // Only what the document that is still open needs is kept
+        assert_eq!(state.documents.keys().collect::<Vec<_>>(), [&lib]);
+        assert!(state.agent_documents.is_empty());
+        assert!(state.shown.is_empty());
+        assert!(state.cycling.is_empty());
+        assert!(state.suggestions.is_empty());
+        assert!(state.panels.is_empty());
+        let mut dirs: Vec<_> = state.editorconfigs.keys().collect();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            [&PathBuf::from("/project"), &PathBuf::from("/project/src")]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use lapce_plugin::{
+    lsp::LspRef,
+    psp_types::{
+        lsp_types::{
+            request::{ExecuteCommand, InlineCompletionRequest},
+            ExecuteCommandParams, InlineCompletionItem, InlineCompletionList,
+            InlineCompletionParams, InlineCompletionResponse, MessageType, Position, Url,
+        },
+        Request,
+    },
+    PLUGIN_RPC,
+};
+use serde_json::Value;
+
+use crate::{settings::Backend, State};
+
+// Newer Copilot language servers support `textDocument/inlineCompletion` themselves, in which case
+// Lapce's requests are passed straight through to them rather than being translated into
+// `getCompletions`. With the `auto` backend the first request finds out which kind of server we
+// have, by whether it succeeds.
+
+impl State {
+    /// Whether inline completion requests should be passed straight through to the server
+    pub(crate) fn uses_native_completion(&self) -> bool {
+        match self.settings.agent.backend {
+            Backend::Auto => self.native_completion != Some(false),
+            Backend::Agent => false,
+            Backend::LanguageServer => true,
+        }
+    }
+
+    /// Pass Lapce's inline completion request `host_id` through to the server
+    pub(crate) fn request_native_completion(
+        &mut self,
+        lsp: LspRef,
+        host_id: u64,
+        params: InlineCompletionParams,
+    ) -> Result<()> {
+        let result = self.request_agent(lsp, InlineCompletionRequest::METHOD, &params);
+        let uri = &params.text_document_position.text_document.uri;
+
+        match result {
+            Ok(result) => {
+                self.native_completion = Some(true);
+                let position = params.text_document_position.position;
+                let result = self.unmask_native_items(uri, position, result)?;
+                PLUGIN_RPC.host_success(host_id, result)?;
+            }
+            // The error of a blocking request doesn't tell us whether the method was unknown, so
+            // with `auto` any failure before the server has answered one is taken to mean that it
+            // doesn't support it
+            Err(err)
+                if self.settings.agent.backend == Backend::Auto
+                    && self.native_completion.is_none() =>
+            {
+                PLUGIN_RPC.window_log_message(
+                    MessageType::INFO,
+                    format!(
+                        "Copilot does not support inlineCompletion ({err}), using getCompletions"
+                    ),
+                )?;
+                self.native_completion = Some(false);
+                self.handle_inline_completion(host_id, params)?;
+            }
+            Err(err) => {
+                PLUGIN_RPC.stderr(&format!("Copilot inlineCompletion failed: {err}"));
+                PLUGIN_RPC.host_success(host_id, InlineCompletionResponse::Array(Vec::new()))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put the secrets back into the items of a native inline completion response for `uri`,
+    /// dropping those that would overwrite one
+    fn unmask_native_items(&self, uri: &Url, position: Position, result: Value) -> Result<Value> {
+        // Nothing was masked, so there is nothing to put back
+        if self.secrets(uri).is_empty() || result.is_null() {
+            return Ok(result);
+        }
+
+        let unmask = |items: Vec<InlineCompletionItem>| -> Vec<InlineCompletionItem> {
+            items
+                .into_iter()
+                .filter_map(|mut item| {
+                    // Items without a range are inserted at the cursor, so don't replace anything
+                    if let Some(range) = item.range {
+                        item.insert_text =
+                            self.unmask_edit(uri, range, position, &item.insert_text)?;
+                    }
+                    Some(item)
+                })
+                .collect()
+        };
+
+        // Items that can't be checked can't be passed on
+        let response = match serde_json::from_value(result) {
+            Ok(response) => response,
+            Err(err) => {
+                PLUGIN_RPC.stderr(&format!("Failed to parse inline completions: {err}"));
+                InlineCompletionResponse::Array(Vec::new())
+            }
+        };
+        let response = match response {
+            InlineCompletionResponse::Array(items) => {
+                InlineCompletionResponse::Array(unmask(items))
+            }
+            InlineCompletionResponse::List(list) => {
+                InlineCompletionResponse::List(InlineCompletionList {
+                    items: unmask(list.items),
+                })
+            }
+        };
+
+        Ok(serde_json::to_value(response)?)
+    }
+
+    /// Pass a command that isn't ours, like those on the items of a native inline completion,
+    /// through to the server
+    pub(crate) fn forward_command(&mut self, params: ExecuteCommandParams) -> Result<()> {
+        let Some(lsp) = self.lsp else {
+            return Ok(());
+        };
+
+        self.send_telemetry::<ExecuteCommand>(lsp, params)
+    }
+}
@@ -0,0 +1,509 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use lapce_plugin::{
+    psp_types::{
+        lsp_types::{
+            request::{ApplyWorkspaceEdit, ShowDocument},
+            ApplyWorkspaceEditParams, ApplyWorkspaceEditResponse, CodeActionOrCommand, Command,
+            MessageType, Position, ShowDocumentParams, ShowDocumentResult, TextEdit, Url,
+            WorkspaceEdit,
+        },
+        Request,
+    },
+    PLUGIN_RPC,
+};
+use serde_json::Value;
+
+use crate::{
+    copilot::{
+        GetPanelCompletions, GetPanelCompletionsParams, GetPanelCompletionsResult,
+        PanelSolutionParams, PanelSolutionsDoneParams,
+    },
+    volt_dir, State,
+};
+
+/// Command to ask Copilot for a panel of solutions.
+/// Arguments are the document uri and the position to complete at.
+pub const OPEN_PANEL_COMMAND: &str = "lapce-copilot.openPanel";
+/// Command to insert a solution from a panel.
+/// Arguments are the document uri and the id of the solution.
+pub const ACCEPT_PANEL_SOLUTION_COMMAND: &str = "lapce-copilot.acceptPanelSolution";
+
+/// Solutions that Copilot has generated for a position in a document.
+/// These are written out to a generated document as they arrive, so that they can be compared.
+pub struct Panel {
+    pub id: String,
+    /// Version of the document that the solutions are for
+    pub version: i32,
+    pub position: Position,
+    pub language_id: String,
+    pub relative_path: String,
+    /// The generated document that shows the solutions, as the host sees it
+    pub path: PathBuf,
+    /// The generated document, as the plugin sees it
+    pub file: PathBuf,
+    /// How many solutions Copilot is trying to find, once it has told us
+    pub target: Option<u32>,
+    /// Solutions, best first
+    pub solutions: Vec<PanelSolutionParams>,
+    /// Set once Copilot has finished, with an error message if it failed
+    pub done: Option<Result<(), String>>,
+}
+
+impl Panel {
+    fn add_solution(&mut self, solution: PanelSolutionParams) {
+        self.solutions
+            .retain(|s| s.solution_id != solution.solution_id);
+        let i = self
+            .solutions
+            .iter()
+            .position(|s| s.score < solution.score)
+            .unwrap_or(self.solutions.len());
+        self.solutions.insert(i, solution);
+    }
+
+    fn render(&self) -> String {
+        let mut out = format!(
+            "# Copilot solutions for {}:{}\n\n",
+            self.relative_path,
+            self.position.line + 1
+        );
+
+        let count = self.solutions.len();
+        let target = self
+            .target
+            .map(|target| target.to_string())
+            .unwrap_or_else(|| "?".to_string());
+        match &self.done {
+            None => out += &format!("Synthesizing {count}/{target} solutions...\n\n"),
+            Some(Ok(())) => out += &format!("Synthesized {count} solutions.\n\n"),
+            Some(Err(message)) => out += &format!("Copilot failed: {message}\n\n"),
+        }
+
+        out += "This document is generated and edits to it are discarded. \
+            Use the 'Insert Copilot solution' code actions in the original document to insert one of these.\n";
+
+        for (i, solution) in self.solutions.iter().enumerate() {
+            let score = solution
+                .score
+                .map(|score| format!("{score:.3}"))
+                .unwrap_or_else(|| "?".to_string());
+            out += &format!(
+                "\n## Solution {} (score {score})\n\n```{}\n{}\n```\n",
+                i + 1,
+                self.language_id,
+                solution.completion_text.trim_end()
+            );
+        }
+
+        out
+    }
+
+    /// Write the panel out to its generated document
+    pub(crate) fn write(&self) -> Result<()> {
+        if let Some(parent) = self.file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The document is left read-only so that it is clear that editing it does nothing, which
+        // means it has to be replaced rather than written over
+        let _ = std::fs::remove_file(&self.file);
+        std::fs::write(&self.file, self.render())?;
+        let mut permissions = std::fs::metadata(&self.file)?.permissions();
+        permissions.set_readonly(true);
+        let _ = std::fs::set_permissions(&self.file, permissions);
+
+        Ok(())
+    }
+
+    /// Delete the generated document
+    pub(crate) fn remove(&self) {
+        let _ = std::fs::remove_file(&self.file);
+    }
+}
+
+/// Panels by the document that they are for
+pub type Panels = HashMap<Url, Panel>;
+
+impl State {
+    /// Ask Copilot for a panel of solutions and show the document that they'll be written to.
+    pub(crate) fn open_panel(&mut self, uri: Url, position: Position) -> Result<()> {
+        let Some(lsp) = self.lsp else {
+            return Ok(());
+        };
+        if !self.agent_state.is_ready() {
+            PLUGIN_RPC.window_show_message(
+                MessageType::INFO,
+                format!("Copilot is not ready: {:?}", self.agent_state),
+            )?;
+            return Ok(());
+        }
+
+        let Some(doc) = self.completions_doc(&uri, position) else {
+            anyhow::bail!("No document for uri: {uri:?}");
+        };
+        let Some(document) = self.documents.get(&uri) else {
+            anyhow::bail!("No document for uri: {uri:?}");
+        };
+        if self.exclusions.is_excluded(&uri) {
+            PLUGIN_RPC.window_show_message(
+                MessageType::INFO,
+                format!("{uri} is excluded from Copilot"),
+            )?;
+            return Ok(());
+        }
+        if self.refuses(&uri) {
+            PLUGIN_RPC.window_show_message(
+                MessageType::INFO,
+                format!("{uri} has secrets in it, so Copilot is not used for it"),
+            )?;
+            return Ok(());
+        }
+        if self.settings.is_language_disabled(&document.language_id) {
+            PLUGIN_RPC.window_show_message(
+                MessageType::INFO,
+                format!("Copilot is disabled for {}", document.language_id),
+            )?;
+            return Ok(());
+        }
+
+        self.next_panel_id += 1;
+        let id = self.next_panel_id.to_string();
+        let relative_path = doc
+            .relative_path
+            .clone()
+            .unwrap_or_else(|| uri.path().to_string());
+        let file_name = relative_path.rsplit('/').next().unwrap_or_default();
+        // The plugin can only get at the volt directory, which it sees as its root
+        let file = PathBuf::from("panels").join(format!("{id}-{file_name}.md"));
+        let path = volt_dir()?.join(&file);
+
+        let panel = Panel {
+            id: id.clone(),
+            version: document.version,
+            position,
+            language_id: document.language_id.clone(),
+            relative_path,
+            path,
+            file,
+            target: None,
+            solutions: Vec::new(),
+            done: None,
+        };
+        panel.write()?;
+
+        let panel_uri = Url::from_file_path(&panel.path)
+            .map_err(|_| anyhow::anyhow!("Bad panel path: {:?}", panel.path))?;
+        if let Some(old) = self.panels.insert(uri.clone(), panel) {
+            old.remove();
+        }
+
+        // Copilot replies with how many solutions to expect, and sends them separately
+        let result = self.request_agent(
+            lsp,
+            GetPanelCompletions::METHOD,
+            GetPanelCompletionsParams {
+                doc,
+                panel_id: id,
+                options: None,
+            },
+        );
+        self.handle_panel_response(uri, result)?;
+
+        let _: ShowDocumentResult = PLUGIN_RPC.send_request_blocking(
+            ShowDocument::METHOD,
+            ShowDocumentParams {
+                uri: panel_uri,
+                external: Some(false),
+                take_focus: Some(true),
+                selection: None,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    fn handle_panel_response(&mut self, uri: Url, result: Result<Value>) -> Result<()> {
+        let Some(panel) = self.panels.get_mut(&uri) else {
+            return Ok(());
+        };
+
+        match result {
+            Ok(result) => {
+                let GetPanelCompletionsResult {
+                    solution_count_target,
+                } = serde_json::from_value(result)?;
+                panel.target = Some(solution_count_target);
+            }
+            Err(err) => panel.done = Some(Err(err.to_string())),
+        }
+
+        panel.write()
+    }
+
+    pub(crate) fn handle_panel_solution(&mut self, solution: PanelSolutionParams) -> Result<()> {
+        let Some(panel) = self
+            .panels
+            .values_mut()
+            .find(|panel| panel.id == solution.panel_id)
+        else {
+            return Ok(());
+        };
+
+        panel.add_solution(solution);
+        panel.write()
+    }
+
+    pub(crate) fn handle_panel_solutions_done(
+        &mut self,
+        params: PanelSolutionsDoneParams,
+    ) -> Result<()> {
+        let Some(panel) = self
+            .panels
+            .values_mut()
+            .find(|panel| panel.id == params.panel_id)
+        else {
+            return Ok(());
+        };
+
+        panel.done = Some(if params.status == "OK" {
+            Ok(())
+        } else {
+            Err(params.message.unwrap_or(params.status))
+        });
+        panel.write()
+    }
+
+    /// Insert a solution from the panel for `uri` into the document
+    pub(crate) fn accept_panel_solution(&mut self, uri: Url, solution_id: &str) -> Result<()> {
+        let Some(panel) = self.panels.get(&uri) else {
+            anyhow::bail!("No Copilot panel for {uri:?}");
+        };
+        let Some(solution) = panel
+            .solutions
+            .iter()
+            .find(|s| s.solution_id == solution_id)
+        else {
+            anyhow::bail!("No Copilot panel solution {solution_id:?} for {uri:?}");
+        };
+
+        // The range of the solution is only valid for the version it was generated for
+        let version = self.documents.get(&uri).map(|d| d.version);
+        if version != Some(panel.version) {
+            PLUGIN_RPC.window_show_message(
+                MessageType::WARNING,
+                "The document has changed since the Copilot panel was opened".to_string(),
+            )?;
+            return Ok(());
+        }
+
+        let Some(new_text) = self.unmask_edit(
+            &uri,
+            solution.range,
+            panel.position,
+            &solution.completion_text,
+        ) else {
+            PLUGIN_RPC.window_show_message(
+                MessageType::WARNING,
+                "The Copilot solution would overwrite a secret, so it can't be inserted"
+                    .to_string(),
+            )?;
+            return Ok(());
+        };
+
+        let edit = TextEdit {
+            range: solution.range,
+            new_text,
+        };
+        let resp: ApplyWorkspaceEditResponse = PLUGIN_RPC.send_request_blocking(
+            ApplyWorkspaceEdit::METHOD,
+            ApplyWorkspaceEditParams {
+                label: Some("Insert Copilot solution".to_string()),
+                edit: WorkspaceEdit {
+                    changes: Some(HashMap::from([(uri, vec![edit])])),
+                    ..Default::default()
+                },
+            },
+        )?;
+
+        if !resp.applied {
+            anyhow::bail!(
+                "Failed to insert Copilot solution: {:?}",
+                resp.failure_reason
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Code actions for opening a panel at `position`, and for inserting the solutions of the
+    /// document's panel
+    pub(crate) fn panel_code_actions(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Vec<CodeActionOrCommand> {
+        if self.is_panel_document(uri) || self.exclusions.is_excluded(uri) {
+            return Vec::new();
+        }
+
+        let mut actions = vec![CodeActionOrCommand::Command(Command {
+            title: "Open Copilot panel".to_string(),
+            command: OPEN_PANEL_COMMAND.to_string(),
+            arguments: Some(vec![
+                uri.to_string().into(),
+                serde_json::to_value(position).unwrap(),
+            ]),
+        })];
+
+        if let Some(panel) = self.panels.get(uri) {
+            for (i, solution) in panel.solutions.iter().enumerate() {
+                actions.push(CodeActionOrCommand::Command(Command {
+                    title: format!("Insert Copilot solution {}", i + 1),
+                    command: ACCEPT_PANEL_SOLUTION_COMMAND.to_string(),
+                    arguments: Some(vec![
+                        uri.to_string().into(),
+                        solution.solution_id.clone().into(),
+                    ]),
+                }));
+            }
+        }
+
+        actions
+    }
+
+    /// Whether the document is one of our generated panels, which shouldn't get completions
+    pub(crate) fn is_panel_document(&self, uri: &Url) -> bool {
+        let Ok(path) = uri.to_file_path() else {
+            return false;
+        };
+
+        self.panels.values().any(|panel| panel.path == path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapce_plugin::psp_types::lsp_types::Range;
+
+    use super::*;
+    use crate::exclude::Exclusions;
+
+    fn panel() -> Panel {
+        Panel {
+            id: "1".to_string(),
+            version: 1,
+            position: Position::new(4, 2),
+            language_id: "rust".to_string(),
+            relative_path: "src/main.rs".to_string(),
+            path: PathBuf::from("/volt/panels/1-main.rs.md"),
+            file: PathBuf::from("panels/1-main.rs.md"),
+            target: Some(3),
+            solutions: Vec::new(),
+            done: None,
+        }
+    }
+
+    fn solution(id: &str, text: &str, score: Option<f64>) -> PanelSolutionParams {
+        PanelSolutionParams {
+            panel_id: "1".to_string(),
+            completion_text: text.to_string(),
+            display_text: text.to_string(),
+            range: Range::new(Position::new(4, 0), Position::new(4, 2)),
+            score,
+            solution_id: id.to_string(),
+        }
+    }
+
+    fn ids(panel: &Panel) -> Vec<&str> {
+        panel
+            .solutions
+            .iter()
+            .map(|s| s.solution_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn solutions_are_kept_best_first() {
+        let mut panel = panel();
+        panel.add_solution(solution("a", "a()", Some(0.2)));
+        panel.add_solution(solution("b", "b()", Some(0.9)));
+        panel.add_solution(solution("c", "c()", None));
+        panel.add_solution(solution("d", "d()", Some(0.5)));
+        assert_eq!(ids(&panel), ["b", "d", "a", "c"]);
+
+        // A solution sent again replaces the old one
+        panel.add_solution(solution("a", "a(1)", Some(1.0)));
+        assert_eq!(ids(&panel), ["a", "b", "d", "c"]);
+        assert_eq!(panel.solutions[0].completion_text, "a(1)");
+    }
+
+    #[test]
+    fn render_shows_progress_and_solutions() {
+        let mut panel = panel();
+        panel.add_solution(solution("a", "  foo();\n", Some(0.75)));
+        panel.add_solution(solution("b", "  bar();", None));
+
+        let rendered = panel.render();
+        assert!(rendered.starts_with("# Copilot solutions for src/main.rs:5\n\n"));
+        assert!(rendered.contains("Synthesizing 2/3 solutions...\n"));
+        assert!(rendered.contains("\n## Solution 1 (score 0.750)\n\n```rust\n  foo();\n```\n"));
+        assert!(rendered.contains("\n## Solution 2 (score ?)\n\n```rust\n  bar();\n```\n"));
+
+        panel.done = Some(Ok(()));
+        assert!(panel.render().contains("Synthesized 2 solutions.\n"));
+
+        panel.done = Some(Err("timed out".to_string()));
+        assert!(panel.render().contains("Copilot failed: timed out\n"));
+    }
+
+    #[test]
+    fn code_actions_offer_the_panel_and_its_solutions() {
+        let mut state = State::default();
+        let uri = Url::parse("file:///project/src/main.rs").unwrap();
+        let position = Position::new(4, 2);
+        assert_eq!(state.panel_code_actions(&uri, position).len(), 1);
+
+        let mut panel = panel();
+        panel.add_solution(solution("a", "a()", Some(0.2)));
+        panel.add_solution(solution("b", "b()", Some(0.9)));
+        state.panels.insert(uri.clone(), panel);
+
+        let titles: Vec<String> = state
+            .panel_code_actions(&uri, position)
+            .into_iter()
+            .map(|action| match action {
+                CodeActionOrCommand::Command(command) => command.title,
+                CodeActionOrCommand::CodeAction(action) => action.title,
+            })
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                "Open Copilot panel",
+                "Insert Copilot solution 1",
+                "Insert Copilot solution 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn no_code_actions_for_panels_or_excluded_documents() {
+        let mut state = State::default();
+        let uri = Url::parse("file:///project/src/main.rs").unwrap();
+        state.panels.insert(uri, panel());
+
+        let panel_uri = Url::parse("file:///volt/panels/1-main.rs.md").unwrap();
+        assert!(state.is_panel_document(&panel_uri));
+        assert!(state
+            .panel_code_actions(&panel_uri, Position::new(0, 0))
+            .is_empty());
+
+        state.exclusions = Exclusions::new(None, "", &["**/*.env".to_string()]);
+        let excluded = Url::parse("file:///project/.env").unwrap();
+        assert!(state
+            .panel_code_actions(&excluded, Position::new(0, 0))
+            .is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use lapce_plugin::{
+    lsp::LspRef,
+    psp_types::{
+        lsp_types::{notification::Exit, request::Shutdown, MessageType},
+        Notification, Request,
+    },
+    PLUGIN_RPC,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    agent::{AgentState, AgentTooOld},
+    start_agent, State,
+};
+
+// The agent is considered to have failed when we can't send it anything, or when several requests
+// in a row fail, like those to a process that has exited. It is then restarted after a wait that
+// doubles with each failure.
+// The plugin has no timers, so the restart happens when the plugin is next woken up after the
+// wait, which is normally by the user typing.
+
+/// How many requests in a row can fail before the agent is restarted
+const MAX_ERRORS: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Failures of the agent, and when to next try restarting it
+#[derive(Default)]
+pub struct Health {
+    /// Requests that have failed in a row
+    errors: u32,
+    /// Restarts since the agent last worked, which the wait before the next one grows with
+    attempts: u32,
+    /// When to restart the agent, if it has failed
+    restart_at: Option<Instant>,
+    /// Whether the user has been told that the agent failed, which they're only told once until
+    /// it is working again
+    notified: bool,
+}
+
+impl State {
+    /// Send a request to the agent and wait for its reply, noting whether the agent is still
+    /// working.  
+    /// The error of a blocking request doesn't say whether it came from Copilot or from the
+    /// connection, so every error counts against the agent.
+    pub(crate) fn request_agent<P: Serialize>(
+        &mut self,
+        lsp: LspRef,
+        method: &str,
+        params: P,
+    ) -> Result<Value> {
+        let result = lsp.send_request_blocking(method, params);
+        match &result {
+            Ok(_) => self.agent_worked(),
+            Err(err) => self.agent_errored(err),
+        }
+
+        Ok(result?)
+    }
+
+    fn agent_worked(&mut self) {
+        self.health.errors = 0;
+        self.health.attempts = 0;
+        self.health.notified = false;
+    }
+
+    fn agent_errored(&mut self, err: &dyn Display) {
+        self.health.errors += 1;
+        if self.health.errors >= MAX_ERRORS {
+            self.agent_failed(err);
+        }
+    }
+
+    /// Pass through the result of sending something to the agent, noting if it couldn't be sent
+    pub(crate) fn check_sent<T, E>(&mut self, result: Result<T, E>) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        if let Err(err) = &result {
+            self.agent_failed(err);
+        }
+
+        Ok(result?)
+    }
+
+    /// Drop the agent and schedule a restart
+    pub(crate) fn agent_failed(&mut self, err: &dyn Display) {
+        if self.lsp.is_none() {
+            return;
+        }
+
+        PLUGIN_RPC.stderr(&format!("Copilot failed: {err}"));
+        if !self.health.notified {
+            self.health.notified = true;
+            let _ = PLUGIN_RPC.window_show_message(
+                MessageType::ERROR,
+                format!("Copilot stopped working ({err}), restarting it"),
+            );
+        }
+
+        if let Err(err) = self.drop_agent(false) {
+            PLUGIN_RPC.stderr(&format!("Failed to drop copilot: {err}"));
+        }
+        if let Err(err) = self.set_agent_state(AgentState::Failed(err.to_string())) {
+            PLUGIN_RPC.stderr(&err.to_string());
+        }
+        self.schedule_restart();
+    }
+
+    fn schedule_restart(&mut self) {
+        let backoff = MIN_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.health.attempts))
+            .min(MAX_BACKOFF);
+        self.health.errors = 0;
+        self.health.restart_at = Some(Instant::now() + backoff);
+    }
+
+    /// Restart the agent if it failed and we've waited long enough
+    pub(crate) fn restart_if_due(&mut self) {
+        let Some(restart_at) = self.health.restart_at else {
+            return;
+        };
+        if Instant::now() < restart_at {
+            return;
+        }
+
+        self.health.restart_at = None;
+        self.health.attempts += 1;
+        PLUGIN_RPC.stderr(&format!(
+            "Restarting copilot, attempt {}",
+            self.health.attempts
+        ));
+
+        // Starting re-runs the handshake and opens the documents again
+        if let Err(err) = start_agent(self) {
+            // Restarting an agent that we refused would only refuse it again
+            if err.is::<AgentTooOld>() {
+                PLUGIN_RPC.stderr(&format!("Not restarting copilot: {err}"));
+                return;
+            }
+
+            if self.lsp.is_some() {
+                self.agent_failed(&err);
+            } else {
+                PLUGIN_RPC.stderr(&format!("Failed to restart copilot: {err}"));
+                self.schedule_restart();
+            }
+        }
+    }
+
+    /// Start a new agent, after dropping everything that was waiting on the current one.
+    pub(crate) fn restart_agent(&mut self) -> Result<()> {
+        self.health = Default::default();
+        self.drop_agent(true)?;
+        start_agent(self)
+    }
+
+    /// Stop the agent and forget everything that belongs to it.  
+    /// A working agent is asked to `shutdown` first, but one that has stopped working is only
+    /// told to exit, as waiting on a reply that may never come would block the plugin.
+    pub(crate) fn drop_agent(&mut self, shutdown: bool) -> Result<()> {
+        // The plugin api has no way to kill a language server, so ask it to exit
+        if let Some(lsp) = self.lsp.take() {
+            if shutdown {
+                if let Err(err) = lsp.send_request_blocking::<_, Value>(Shutdown::METHOD, ()) {
+                    PLUGIN_RPC.stderr(&format!("Failed to shut down copilot: {err}"));
+                }
+            }
+            if let Err(err) = lsp.send_notification(Exit::METHOD, ()) {
+                PLUGIN_RPC.stderr(&format!("Failed to exit copilot: {err}"));
+            }
+        }
+        self.agent_documents.clear();
+        self.native_completion = None;
+        self.sign_in = None;
+
+        // The uuids of completions belong to the agent that made them
+        self.shown.clear();
+        self.suggestions.clear();
+        self.cycling.clear();
+        for panel in self.panels.values_mut() {
+            if panel.done.is_none() {
+                panel.done = Some(Err("Copilot was restarted".to_string()));
+                let _ = panel.write();
+            }
+        }
+
+        Ok(())
+    }
+}
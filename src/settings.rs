@@ -0,0 +1,274 @@
+use lapce_plugin::PLUGIN_RPC;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use crate::copilot::{AuthProvider, EditorConfiguration, LanguageId};
+
+/// The plugin's settings, from the `config` entries in `volt.toml`.
+/// Lapce gives these to us nested by the dots in their names, so `node.path` is `node: { path }`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub node: NodeSettings,
+    pub editor: EditorSettings,
+    pub exclude: ExcludeSettings,
+    pub redact: RedactSettings,
+    pub proxy: ProxySettings,
+    pub auth: AuthSettings,
+    pub agent: AgentSettings,
+}
+
+impl Settings {
+    /// Parse the settings from the initialization options.
+    /// Each section is parsed on its own, so a malformed value only resets its own section to
+    /// the defaults.
+    pub fn from_options(options: Option<&Value>) -> Settings {
+        let Some(options) = options else {
+            return Settings::default();
+        };
+
+        Settings {
+            node: section(options, "node"),
+            editor: section(options, "editor"),
+            exclude: section(options, "exclude"),
+            redact: section(options, "redact"),
+            proxy: section(options, "proxy"),
+            auth: section(options, "auth"),
+            agent: section(options, "agent"),
+        }
+    }
+
+    /// The path to the node executable, which by default is found on the path
+    pub fn node_path(&self) -> &str {
+        if self.node.path.is_empty() {
+            "node"
+        } else {
+            &self.node.path
+        }
+    }
+
+    /// The path to a custom agent, instead of the bundled `dist/agent.js`
+    pub fn agent_path(&self) -> Option<&str> {
+        let path = self.agent.path.trim();
+        (!path.is_empty()).then_some(path)
+    }
+
+    /// Whether the user has turned off completions for documents of `language_id`
+    pub fn is_language_disabled(&self, language_id: &str) -> bool {
+        self.editor
+            .disabled_languages
+            .iter()
+            .any(|language| language == language_id)
+    }
+
+    /// The GitHub Enterprise Server to sign in with, instead of github.com
+    pub fn auth_provider(&self) -> Option<AuthProvider> {
+        let url = self.auth.enterprise_url.trim();
+        if url.is_empty() {
+            return None;
+        }
+
+        Some(AuthProvider {
+            url: Some(url.to_string()),
+        })
+    }
+
+    /// The configuration to send to Copilot with `setEditorInfo`
+    pub fn editor_configuration(&self) -> EditorConfiguration {
+        let editor = &self.editor;
+
+        EditorConfiguration {
+            show_editor_completions: Some(editor.show_editor_completions),
+            enable_auto_completions: Some(editor.enable_auto_completions),
+            delay_completions: Some(editor.delay_completions),
+            filter_completions: Some(editor.filter_completions),
+            disabled_languages: Some(
+                editor
+                    .disabled_languages
+                    .iter()
+                    .map(|language| LanguageId {
+                        language_id: language.clone(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Parse the `name` section of the settings, falling back to its defaults if it is malformed
+fn section<T: DeserializeOwned + Default>(options: &Value, name: &str) -> T {
+    let Some(value) = options.get(name) else {
+        return T::default();
+    };
+
+    match T::deserialize(value) {
+        Ok(section) => section,
+        Err(err) => {
+            PLUGIN_RPC.stderr(&format!(
+                "Failed to parse {name} settings, using defaults: {err}"
+            ));
+            T::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NodeSettings {
+    /// Empty to use the `node` on the path
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AgentSettings {
+    /// Empty to use the bundled agent
+    pub path: String,
+    pub args: Vec<String>,
+    /// `NAME=value`
+    pub env: Vec<String>,
+    pub backend: Backend,
+}
+
+/// How inline completions are requested from the agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Backend {
+    /// Try `textDocument/inlineCompletion`, falling back to `getCompletions` if the agent doesn't
+    /// know it
+    #[default]
+    Auto,
+    /// `getCompletions`, for the `agent.js` of copilot.vim
+    Agent,
+    /// `textDocument/inlineCompletion`, for the Copilot language server
+    LanguageServer,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExcludeSettings {
+    /// Globs of files that are never sent to Copilot, in addition to the workspace's
+    /// `.copilotignore`
+    pub globs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RedactSettings {
+    pub mode: RedactMode,
+    /// Regexes of secrets, in addition to the built in ones
+    pub patterns: Vec<String>,
+}
+
+/// What to do with secrets in documents
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RedactMode {
+    /// Mask them before sending the document to Copilot
+    #[default]
+    Mask,
+    /// Mask them, and don't ask for completions in documents that have them
+    Refuse,
+    /// Send them as they are
+    Off,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AuthSettings {
+    /// Url of a GitHub Enterprise Server, empty for github.com
+    pub enterprise_url: String,
+}
+
+/// The proxy that Copilot connects through. Without a host the environment is used instead.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProxySettings {
+    pub host: String,
+    /// 0 for 80
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// Whether to check the proxy's certificate, which some corporate proxies need turned off
+    pub reject_unauthorized: bool,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        ProxySettings {
+            host: String::new(),
+            port: 0,
+            username: String::new(),
+            password: String::new(),
+            reject_unauthorized: true,
+        }
+    }
+}
+
+/// Settings that are passed on to Copilot as the [`EditorConfiguration`]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct EditorSettings {
+    pub show_editor_completions: bool,
+    pub enable_auto_completions: bool,
+    pub delay_completions: bool,
+    pub filter_completions: bool,
+    pub disabled_languages: Vec<String>,
+}
+
+impl Default for EditorSettings {
+    fn default() -> Self {
+        EditorSettings {
+            show_editor_completions: true,
+            enable_auto_completions: true,
+            delay_completions: false,
+            filter_completions: true,
+            disabled_languages: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn malformed_section_keeps_the_others() {
+        let options = json!({
+            "node": { "path": "/usr/bin/node" },
+            "proxy": { "port": "eighty" },
+        });
+
+        let settings = Settings::from_options(Some(&options));
+
+        assert_eq!(settings.node.path, "/usr/bin/node");
+        assert_eq!(settings.proxy, ProxySettings::default());
+    }
+
+    #[test]
+    fn missing_options_are_defaults() {
+        assert_eq!(Settings::from_options(None), Settings::default());
+        assert_eq!(
+            Settings::from_options(Some(&Value::Null)),
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn disabled_languages_are_sent_as_language_ids() {
+        let options = json!({
+            "editor": { "disabledLanguages": ["markdown"] },
+        });
+
+        let settings = Settings::from_options(Some(&options));
+        assert!(settings.is_language_disabled("markdown"));
+        assert!(!settings.is_language_disabled("rust"));
+
+        let configuration = serde_json::to_value(settings.editor_configuration()).unwrap();
+        assert_eq!(
+            configuration["disabledLanguages"],
+            json!([{ "languageId": "markdown" }])
+        );
+    }
+}
@@ -0,0 +1,309 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use lapce_plugin::{
+    lsp::LspRef,
+    psp_types::{
+        lsp_types::{MessageType, Url},
+        Request,
+    },
+    PLUGIN_RPC,
+};
+use serde_json::Value;
+
+use crate::{
+    agent::AgentState,
+    copilot::{
+        CheckAuthStatus, CheckAuthStatusParams, CheckAuthStatusResult, SignInConfirm,
+        SignInConfirmParams, SignInConfirmResult, SignInInitiate, SignInInitiateParams,
+        SignInInitiateResult, SignInStatus,
+    },
+    open, State,
+};
+
+// `signInConfirm` isn't answered until the user has signed in, and the plugin api can only wait
+// on requests, which would stop the plugin from handling anything else until then. So it is only
+// sent once the user says that they have entered the code, by invoking completion. An expired code
+// is replaced at that point too, without opening the browser again.
+// In case the user signs in some other way we also ask `checkStatus` when woken up, no more often
+// than GitHub's polling interval.
+
+/// How often to check whether the user has signed in, if GitHub doesn't say
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the code lasts, if GitHub doesn't say
+const DEFAULT_EXPIRES_IN: Duration = Duration::from_secs(15 * 60);
+
+/// A device flow sign-in that is waiting on the user
+pub struct SignIn {
+    user_code: String,
+    host: String,
+    expires_at: Instant,
+    interval: Duration,
+    last_checked: Instant,
+}
+
+impl SignIn {
+    fn minutes_left(&self) -> u64 {
+        let left = self.expires_at.saturating_duration_since(Instant::now());
+        left.as_secs().div_ceil(60)
+    }
+
+    fn expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    fn instructions(&self) -> String {
+        format!(
+            "Input this code at {}: {}, then invoke completion to finish signing in to Copilot. \
+             It expires in {} minutes.",
+            self.host,
+            self.user_code,
+            self.minutes_left()
+        )
+    }
+}
+
+impl State {
+    /// Start signing in with the device flow, which is finished by `confirm_sign_in`.  
+    /// The browser is only opened for the first code, as it is still open for any later ones.
+    pub(crate) fn start_sign_in(&mut self, lsp: LspRef) -> Result<()> {
+        if self.agent_state != AgentState::Authenticating {
+            self.set_agent_state(AgentState::Authenticating)?;
+        }
+
+        // This only asks GitHub for a code, so it doesn't take long
+        let resp: SignInInitiateResult =
+            lsp.send_request_blocking(SignInInitiate::METHOD, SignInInitiateParams {})?;
+
+        match resp.status {
+            SignInStatus::AlreadySignedIn => {
+                PLUGIN_RPC.window_log_message(
+                    MessageType::WARNING,
+                    "Already signed-in despite checking that..".to_string(),
+                )?;
+                self.finish_sign_in(resp.user)
+            }
+            SignInStatus::PromptUserDeviceFlow => {
+                let Some(verification_uri) = &resp.verification_uri else {
+                    PLUGIN_RPC.window_log_message(
+                        MessageType::ERROR,
+                        "No verification uri".to_string(),
+                    )?;
+                    anyhow::bail!("No verification uri: {resp:?}");
+                };
+                let Some(user_code) = &resp.user_code else {
+                    PLUGIN_RPC.window_log_message(
+                        MessageType::ERROR,
+                        "No user code for sign-in".to_string(),
+                    )?;
+                    anyhow::bail!("No user code: {resp:?}");
+                };
+                let host = Url::parse(verification_uri)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_else(|| verification_uri.clone());
+                if let Some(enterprise_url) = self.settings.auth_provider().and_then(|p| p.url) {
+                    if !enterprise_url.contains(&host) {
+                        PLUGIN_RPC.stderr(&format!(
+                            "Copilot is signing in at {host} rather than {enterprise_url}"
+                        ));
+                    }
+                }
+
+                let now = Instant::now();
+                let seconds = |s: Option<f32>| {
+                    s.and_then(|s| Duration::try_from_secs_f32(s).ok())
+                        .filter(|s| !s.is_zero())
+                };
+                let expires_at = seconds(resp.expires_in)
+                    .and_then(|expires_in| now.checked_add(expires_in))
+                    .unwrap_or(now + DEFAULT_EXPIRES_IN);
+                let renewing = self.sign_in.is_some();
+                let sign_in = SignIn {
+                    user_code: user_code.clone(),
+                    host,
+                    expires_at,
+                    interval: seconds(resp.interval).unwrap_or(DEFAULT_INTERVAL),
+                    last_checked: now,
+                };
+                PLUGIN_RPC.window_show_message(MessageType::INFO, sign_in.instructions())?;
+                self.sign_in = Some(sign_in);
+
+                if renewing {
+                    return Ok(());
+                }
+                open(verification_uri)
+            }
+        }
+    }
+
+    /// Check whether the user has signed in some other way when woken up
+    pub(crate) fn check_sign_in(&mut self) {
+        let Some(lsp) = self.lsp else {
+            return;
+        };
+        let Some(sign_in) = &mut self.sign_in else {
+            return;
+        };
+
+        let now = Instant::now();
+        if sign_in.expired() || now.duration_since(sign_in.last_checked) < sign_in.interval {
+            return;
+        }
+        sign_in.last_checked = now;
+
+        let result = self.request_agent(
+            lsp,
+            CheckAuthStatus::METHOD,
+            CheckAuthStatusParams { options: None },
+        );
+        if let Err(err) = self.handle_auth_status(result) {
+            PLUGIN_RPC.stderr(&format!("Failed to check copilot sign-in: {err}"));
+        }
+    }
+
+    /// Finish signing in, once the user has invoked completion to say that they've entered the
+    /// code. This waits on GitHub, so it blocks until the user has signed in or the code expires.  
+    /// If the code has already expired then a new one is shown instead.
+    pub(crate) fn confirm_sign_in(&mut self) -> Result<()> {
+        let (Some(lsp), Some(sign_in)) = (self.lsp, &self.sign_in) else {
+            return Ok(());
+        };
+
+        if sign_in.expired() {
+            PLUGIN_RPC.window_show_message(
+                MessageType::WARNING,
+                "The Copilot sign-in code expired, getting a new one".to_string(),
+            )?;
+            return self.start_sign_in(lsp);
+        }
+
+        PLUGIN_RPC.window_show_message(
+            MessageType::INFO,
+            format!(
+                "Waiting for GitHub to accept the code {}",
+                sign_in.user_code
+            ),
+        )?;
+        let result = self.request_agent(lsp, SignInConfirm::METHOD, SignInConfirmParams {});
+        self.handle_sign_in_confirm(result)
+    }
+
+    fn handle_sign_in_confirm(&mut self, result: Result<Value>) -> Result<()> {
+        let resp =
+            result.and_then(|result| Ok(serde_json::from_value::<SignInConfirmResult>(result)?));
+        match resp {
+            Ok(resp) if resp.status == "OK" => self.finish_sign_in(resp.user),
+            Ok(resp) => {
+                self.sign_in_failed(&resp.status);
+                Ok(())
+            }
+            // An expired code is replaced when the user next invokes completion
+            Err(_) if self.sign_in.as_ref().is_some_and(SignIn::expired) => {
+                PLUGIN_RPC.window_show_message(
+                    MessageType::WARNING,
+                    "The Copilot sign-in code expired, invoke completion to get a new one"
+                        .to_string(),
+                )?;
+                Ok(())
+            }
+            Err(err) => {
+                self.sign_in_failed(&err.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_auth_status(&mut self, result: Result<Value>) -> Result<()> {
+        // Errors are left for `signInConfirm` to report
+        let Ok(result) = result else {
+            return Ok(());
+        };
+        let status: CheckAuthStatusResult = serde_json::from_value(result)?;
+        if !status.status.is_ok() {
+            return Ok(());
+        }
+
+        self.finish_sign_in(status.user)
+    }
+
+    fn finish_sign_in(&mut self, user: Option<String>) -> Result<()> {
+        self.set_agent_state(AgentState::Ready)?;
+        let was_waiting = self.sign_in.take().is_some();
+
+        if was_waiting {
+            let message = match user {
+                Some(user) => format!("Signed in to Copilot as {user}"),
+                None => "Signed in to Copilot".to_string(),
+            };
+            PLUGIN_RPC.window_show_message(MessageType::INFO, message)?;
+        }
+
+        Ok(())
+    }
+
+    fn sign_in_failed(&mut self, err: &str) {
+        self.sign_in = None;
+        let message = format!("Failed to sign in to Copilot: {err}");
+        let _ = PLUGIN_RPC.window_show_message(MessageType::ERROR, message.clone());
+        if let Err(err) = self.set_agent_state(AgentState::Failed(message)) {
+            PLUGIN_RPC.stderr(&err.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiting_sign_in() -> SignIn {
+        let now = Instant::now();
+        SignIn {
+            user_code: "ABCD-1234".to_string(),
+            host: "github.com".to_string(),
+            expires_at: now + DEFAULT_EXPIRES_IN,
+            interval: DEFAULT_INTERVAL,
+            last_checked: now,
+        }
+    }
+
+    #[test]
+    fn sign_in_makes_the_agent_ready() {
+        let mut state = State {
+            agent_state: AgentState::Authenticating,
+            sign_in: Some(waiting_sign_in()),
+            ..Default::default()
+        };
+
+        state.finish_sign_in(Some("octocat".to_string())).unwrap();
+
+        assert_eq!(state.agent_state, AgentState::Ready);
+        assert!(state.sign_in.is_none());
+    }
+
+    #[test]
+    fn sign_in_does_not_revive_a_failed_agent() {
+        let failed = AgentState::Failed("crashed".to_string());
+        let mut state = State {
+            agent_state: failed.clone(),
+            ..Default::default()
+        };
+
+        assert!(state.finish_sign_in(None).is_err());
+        assert_eq!(state.agent_state, failed);
+    }
+
+    #[test]
+    fn failed_sign_in_fails_the_agent() {
+        let mut state = State {
+            agent_state: AgentState::Authenticating,
+            sign_in: Some(waiting_sign_in()),
+            ..Default::default()
+        };
+
+        state.sign_in_failed("access denied");
+
+        assert!(matches!(state.agent_state, AgentState::Failed(_)));
+        assert!(state.sign_in.is_none());
+    }
+}